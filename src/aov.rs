@@ -0,0 +1,149 @@
+use glam::Vec3;
+use std::io::{BufWriter, Write};
+
+/// What the camera sees first through a sample, ignoring how light bounces after it
+#[derive(Clone, Copy, Debug)]
+pub struct SurfaceSample {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub position: Vec3,
+    /// Distance from the camera along the direction it is looking in
+    pub depth: f32,
+    pub instance: Option<u32>,
+    pub material: Option<u32>,
+}
+
+/// Auxiliary values accumulated per pixel next to the beauty image
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AovPixel {
+    albedo: Vec3,
+    normal: Vec3,
+    position: Vec3,
+    depth: f32,
+    /// Number of samples that hit a surface, the surface values are averaged over these
+    hits: u32,
+    /// Light that reached the camera after at most one bounce
+    direct: Vec3,
+    /// Light that reached the camera after two or more bounces
+    indirect: Vec3,
+    /// Number of samples, the lighting is averaged over these
+    samples: u32,
+    /// IDs can not be averaged, so the first surface the pixel saw is kept
+    instance: Option<u32>,
+    material: Option<u32>,
+}
+
+impl AovPixel {
+    pub fn add_sample(&mut self, surface: Option<SurfaceSample>, direct: Vec3, indirect: Vec3) {
+        if let Some(surface) = surface {
+            self.albedo += surface.albedo;
+            self.normal += surface.normal;
+            self.position += surface.position;
+            self.depth += surface.depth;
+            if self.hits == 0 {
+                self.instance = surface.instance;
+                self.material = surface.material;
+            }
+            self.hits += 1;
+        }
+
+        self.direct += direct;
+        self.indirect += indirect;
+        self.samples += 1;
+    }
+
+    /// The averaged value of a pass, scalar passes are repeated over all channels
+    pub fn value(&self, aov: Aov) -> Vec3 {
+        let hits = self.hits.max(1) as f32;
+        let samples = self.samples.max(1) as f32;
+        let id = |id: Option<u32>| Vec3::splat(id.map_or(-1.0, |id| id as f32));
+
+        match aov {
+            Aov::Albedo => self.albedo / hits,
+            Aov::Normal if self.hits > 0 => self.normal.normalize(),
+            Aov::Normal => Vec3::zero(),
+            Aov::Depth => Vec3::splat(self.depth / hits),
+            Aov::Position => self.position / hits,
+            Aov::Instance => id(self.instance),
+            Aov::Material => id(self.material),
+            Aov::Direct => self.direct / samples,
+            Aov::Indirect => self.indirect / samples,
+        }
+    }
+}
+
+/// The auxiliary passes that can be written next to the beauty image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    /// Index of the instance, -1 where nothing was hit
+    Instance,
+    /// Index of the material in the order materials appear in the scene, -1 where nothing was hit
+    Material,
+    Direct,
+    Indirect,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Instance,
+        Aov::Material,
+        Aov::Direct,
+        Aov::Indirect,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Instance => "instance",
+            Aov::Material => "material",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+        }
+    }
+
+    /// Whether the pass holds a single value per pixel
+    pub fn is_scalar(self) -> bool {
+        matches!(self, Aov::Depth | Aov::Instance | Aov::Material)
+    }
+}
+
+/// Writes a portable float map, with one channel for scalar images and three otherwise.
+/// Pixels are given row by row from the top, PFM stores them from the bottom.
+pub fn save_pfm(
+    path: &str,
+    width: u32,
+    height: u32,
+    pixels: &[Vec3],
+    scalar: bool,
+) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(std::fs::File::create(path)?);
+
+    // A negative scale marks the data as little endian
+    let kind = if scalar { "Pf" } else { "PF" };
+    write!(writer, "{}\n{} {}\n-1.0\n", kind, width, height)?;
+    for row in pixels.chunks(width as usize).rev() {
+        for pixel in row {
+            if scalar {
+                writer.write_all(&pixel.x().to_le_bytes())?;
+            } else {
+                for channel in [pixel.x(), pixel.y(), pixel.z()] {
+                    writer.write_all(&channel.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
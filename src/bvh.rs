@@ -1,9 +1,10 @@
 use crate::{
-    primitives::{Instance, AABB},
+    primitives::{Aabb, Instance},
     Hit, Intersect, Ray,
 };
 use glam::Vec3;
 use rayon::prelude::*;
+use std::cmp::Ordering;
 
 #[derive(Clone, Copy, Debug)]
 pub enum Axis {
@@ -31,14 +32,17 @@ impl GetAxis for Vec3 {
 }
 
 /// A Bounding Volume Hierarchy
-pub struct BVH {
+#[derive(Debug)]
+pub struct Bvh {
     /// The primitives that make up the scene
     geometry: Vec<Instance>,
+    /// Index each primitive had in the scene before it was sorted
+    ids: Vec<u32>,
     /// The BVH tree
     tree: Vec<FlatNode>,
 }
 
-impl BVH {
+impl Bvh {
     pub fn new(geometry: Vec<Instance>) -> Self {
         assert!(!geometry.is_empty());
 
@@ -64,7 +68,7 @@ impl BVH {
             })
             .collect::<Vec<_>>();
 
-        let root = BVH::build(
+        let root = Bvh::build(
             &mut build_geometry,
             &mut index_to_geometry,
             &mut total_nodes,
@@ -75,6 +79,7 @@ impl BVH {
         let tree = Self::flatten(root, total_nodes);
 
         // Sort the geometry by the indices in index_to_geometry
+        let ids = index_to_geometry.iter().map(|&i| i as u32).collect();
         let geometry = index_to_geometry
             .into_iter()
             .map(|i| geometry.get(i).unwrap())
@@ -83,7 +88,11 @@ impl BVH {
 
         println!("Total Nodes Built: {}", total_nodes);
 
-        Self { geometry, tree }
+        Self {
+            geometry,
+            ids,
+            tree,
+        }
     }
 
     fn build(
@@ -97,21 +106,20 @@ impl BVH {
         // Create bounding box for all geometry in this BuildNode
         let bounds = geometry
             .iter()
-            .fold(AABB::default(), |b, g| b.union(g.geom.bounds().unwrap()));
+            .fold(Aabb::default(), |b, g| b.union(g.geom.bounds().unwrap()));
 
         // Check if we are a leaf
         if geometry.len() == 1 {
-            return BVH::build_leaf(geometry, index_to_geometry, bounds);
+            return Bvh::build_leaf(geometry, index_to_geometry, bounds);
         }
 
         // Create centroids for all geometry in this BuildNode
         let centroids = geometry
             .iter()
-            .fold(AABB::default(), |b, g| b.point_union(g.center));
+            .fold(Aabb::default(), |b, g| b.point_union(g.center));
 
         // Decide which axis to spilt the scene along
         let split_axis = bounds.max_extent();
-        let mid;
 
         // SAH guided partitioning
         let mut buckets = [SAHBucket::default(); 12];
@@ -153,19 +161,14 @@ impl BVH {
             })
             .collect();
 
-        let (min_bucket, min_cost) =
-            cost.iter()
-                .enumerate()
-                .fold((0, std::f32::INFINITY), |(pi, pc), (i, c)| {
-                    if *c < pc {
-                        (i, *c)
-                    } else {
-                        (pi, pc)
-                    }
-                });
+        let (min_bucket, &min_cost) = cost
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .unwrap();
 
         // Check if we should build an interior node based on cost and the split_threshold
-        if geometry.len() > split_threshold || min_cost < geometry.len() as f32 {
+        let mid = if geometry.len() > split_threshold || min_cost < geometry.len() as f32 {
             // Partition the geometry into a half that fails the predicate, and a half that
             // satisfies it. Then return the index of the first element to satisfies the predicate
 
@@ -178,22 +181,22 @@ impl BVH {
             };
 
             geometry.par_sort_unstable_by_key(func);
-            mid = geometry.iter().position(func).unwrap_or(geometry.len() / 2);
+            geometry.iter().position(func).unwrap_or(geometry.len() / 2)
         } else {
-            return BVH::build_leaf(geometry, index_to_geometry, bounds);
-        }
+            return Bvh::build_leaf(geometry, index_to_geometry, bounds);
+        };
 
         println!("Geometry count: {}\nMid: {}", geometry.len(), mid);
 
         // Assert that mid can be used to make valid ranges
         assert!(mid != 0 && mid != geometry.len());
-        let left = Box::new(BVH::build(
+        let left = Box::new(Bvh::build(
             &mut geometry[..mid],
             index_to_geometry,
             total_nodes,
             split_threshold,
         ));
-        let right = Box::new(BVH::build(
+        let right = Box::new(Bvh::build(
             &mut geometry[mid..],
             index_to_geometry,
             total_nodes,
@@ -205,7 +208,7 @@ impl BVH {
     fn build_leaf(
         geometry: &mut [GeometryInfo<'_>],
         index_to_geometry: &mut Vec<usize>,
-        bounds: AABB,
+        bounds: Aabb,
     ) -> BuildNode {
         let geometry_offset = index_to_geometry.len();
         index_to_geometry.append(&mut geometry.iter().map(|g| g.index).collect());
@@ -250,12 +253,13 @@ impl BVH {
     }
 }
 
-impl Intersect for BVH {
+impl Intersect for Bvh {
     fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
         fn intersect(
             node: &FlatNode,
             tree: &[FlatNode],
             geometry: &[impl Intersect],
+            ids: &[u32],
             ray: Ray,
             t_min: f32,
             t_max: f32,
@@ -263,14 +267,14 @@ impl Intersect for BVH {
             if node.bounds.has_intersection(ray, t_min, t_max) {
                 match node.inner {
                     FlatNodeInner::Interior { left, right, .. } => {
-                        let left = tree
-                            .get(left)
-                            .and_then(|node| intersect(node, tree, geometry, ray, t_min, t_max));
-                        let right = tree
-                            .get(right)
-                            .and_then(|node| intersect(node, tree, geometry, ray, t_min, t_max));
-
-                        return match (left, right) {
+                        let left = tree.get(left).and_then(|node| {
+                            intersect(node, tree, geometry, ids, ray, t_min, t_max)
+                        });
+                        let right = tree.get(right).and_then(|node| {
+                            intersect(node, tree, geometry, ids, ray, t_min, t_max)
+                        });
+
+                        match (left, right) {
                             (Some(left), Some(right)) => {
                                 if left.t < right.t {
                                     Some(left)
@@ -280,7 +284,7 @@ impl Intersect for BVH {
                             }
                             (left, None) => left,
                             (None, right) => right,
-                        };
+                        }
                     }
                     FlatNodeInner::Leaf {
                         geometry_offset,
@@ -290,16 +294,16 @@ impl Intersect for BVH {
                         let mut closest = t_max;
 
                         // Find the closest intersection
-                        for primitive in
-                            &geometry[geometry_offset..geometry_offset + num_primitives]
-                        {
-                            if let Some(h) = primitive.intersection(ray, t_min, closest) {
+                        let range = geometry_offset..geometry_offset + num_primitives;
+                        for (primitive, &id) in geometry[range.clone()].iter().zip(&ids[range]) {
+                            if let Some(mut h) = primitive.intersection(ray, t_min, closest) {
                                 closest = h.t;
+                                h.instance = Some(id);
                                 hit = Some(h);
                             }
                         }
 
-                        return hit;
+                        hit
                     }
                 }
             } else {
@@ -307,16 +311,24 @@ impl Intersect for BVH {
             }
         }
 
-        self.tree
-            .first()
-            .and_then(|node| intersect(node, &self.tree, &self.geometry, ray, t_min, t_max))
+        self.tree.first().and_then(|node| {
+            intersect(
+                node,
+                &self.tree,
+                &self.geometry,
+                &self.ids,
+                ray,
+                t_min,
+                t_max,
+            )
+        })
     }
 
     fn has_intersection(&self, _ray: Ray, _t_min: f32, _t_max: f32) -> bool {
         unimplemented!()
     }
 
-    fn bounds(&self) -> Option<AABB> {
+    fn bounds(&self) -> Option<Aabb> {
         self.tree.first().map(|node| node.bounds)
     }
 }
@@ -325,13 +337,13 @@ struct GeometryInfo<'a> {
     geom: &'a Instance,
     index: usize,
     center: Vec3,
-    bounds: AABB,
+    bounds: Aabb,
 }
 
 #[derive(Copy, Clone, Debug, Default)]
 struct SAHBucket {
     count: usize,
-    bounds: AABB,
+    bounds: Aabb,
 }
 
 #[derive(Debug)]
@@ -348,7 +360,7 @@ enum BuildNodeInner {
 
 #[derive(Debug)]
 struct BuildNode {
-    bounds: AABB,
+    bounds: Aabb,
     inner: BuildNodeInner,
 }
 
@@ -362,7 +374,7 @@ impl BuildNode {
         }
     }
 
-    fn leaf(bounds: AABB, geometry_offset: usize, num_primitives: usize) -> Self {
+    fn leaf(bounds: Aabb, geometry_offset: usize, num_primitives: usize) -> Self {
         Self {
             bounds,
             inner: BuildNodeInner::Leaf {
@@ -387,19 +399,19 @@ enum FlatNodeInner {
 
 #[derive(Debug)]
 struct FlatNode {
-    bounds: AABB,
+    bounds: Aabb,
     inner: FlatNodeInner,
 }
 
 impl FlatNode {
-    fn interior(bounds: AABB, left: usize, right: usize) -> Self {
+    fn interior(bounds: Aabb, left: usize, right: usize) -> Self {
         Self {
             bounds,
             inner: FlatNodeInner::Interior { left, right },
         }
     }
 
-    fn leaf(bounds: AABB, geometry_offset: usize, num_primitives: usize) -> Self {
+    fn leaf(bounds: Aabb, geometry_offset: usize, num_primitives: usize) -> Self {
         Self {
             bounds,
             inner: FlatNodeInner::Leaf {
@@ -0,0 +1,145 @@
+use crate::sampler::Sampler;
+use anyhow::ensure;
+use std::f32::consts::PI;
+
+/// The shape of the opening of a lens, which out of focus highlights take the shape of
+#[derive(Clone, Debug)]
+pub enum Aperture {
+    Circle,
+    /// A regular polygon formed by the blades of the diaphragm, rotation is in radians
+    Polygon {
+        blades: u32,
+        rotation: f32,
+    },
+    /// Lets light through proportionally to the brightness of an image
+    Image(ApertureImage),
+}
+
+impl Aperture {
+    /// Samples a point on the aperture, within the unit disk
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (f32, f32) {
+        let (u1, u2) = sampler.next_2d();
+
+        match self {
+            Aperture::Circle => concentric_disk(u1, u2),
+            Aperture::Polygon { blades, rotation } => polygon(*blades, *rotation, u1, u2),
+            Aperture::Image(image) => image.sample(u1, u2),
+        }
+    }
+}
+
+/// Maps the unit square to the unit disk while keeping areas and neighbourhoods intact,
+/// from "A Low Distortion Map Between Disk and Square" by Shirley and Chiu
+pub fn concentric_disk(u1: f32, u2: f32) -> (f32, f32) {
+    let (x, y) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+    if x == 0.0 && y == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, PI / 4.0 * (y / x))
+    } else {
+        (y, PI / 2.0 - PI / 4.0 * (x / y))
+    };
+
+    (r * theta.cos(), r * theta.sin())
+}
+
+// Uniformly samples a regular polygon inscribed in the unit circle, by picking one of the
+// triangles between its center and edges and then a point inside of it
+fn polygon(blades: u32, rotation: f32, u1: f32, u2: f32) -> (f32, f32) {
+    let blades = blades.max(3);
+    let scaled = u1 * blades as f32;
+    let triangle = (scaled as u32).min(blades - 1);
+    let u1 = scaled - triangle as f32;
+
+    let corner = |i: u32| {
+        let angle = rotation + 2.0 * PI * i as f32 / blades as f32;
+        (angle.cos(), angle.sin())
+    };
+    let (a, b) = (corner(triangle), corner(triangle + 1));
+
+    // The square root spreads points evenly towards the wide end of the triangle
+    let distance = u1.sqrt();
+    (
+        distance * ((1.0 - u2) * a.0 + u2 * b.0),
+        distance * ((1.0 - u2) * a.1 + u2 * b.1),
+    )
+}
+
+/// A grayscale image stretched over the square around the unit disk, sampled proportionally
+/// to its brightness with a row distribution and a column distribution per row
+#[derive(Clone, Debug)]
+pub struct ApertureImage {
+    width: usize,
+    height: usize,
+    /// Cumulative distribution over the rows, from the bottom
+    rows: Vec<f32>,
+    /// Cumulative distribution over the columns of every row
+    columns: Vec<Vec<f32>>,
+}
+
+impl ApertureImage {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let image = image::open(path)?.to_luma8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+
+        // Images are stored from the top, the aperture goes from the bottom like the film
+        let mut columns = Vec::with_capacity(height);
+        let mut row_sums = Vec::with_capacity(height);
+        for y in (0..height).rev() {
+            let values = (0..width).map(|x| image.get_pixel(x as u32, y as u32)[0] as f32);
+            let (cdf, sum) = cumulative(values);
+            columns.push(cdf);
+            row_sums.push(sum);
+        }
+        let (rows, total) = cumulative(row_sums.into_iter());
+        ensure!(total > 0.0, "Aperture image {} is completely black", path);
+
+        Ok(Self {
+            width,
+            height,
+            rows,
+            columns,
+        })
+    }
+
+    fn sample(&self, u1: f32, u2: f32) -> (f32, f32) {
+        let (row, y) = sample_cdf(&self.rows, u2);
+        let (_, x) = sample_cdf(&self.columns[row], u1);
+
+        (
+            2.0 * x / self.width as f32 - 1.0,
+            2.0 * y / self.height as f32 - 1.0,
+        )
+    }
+}
+
+// Normalized cumulative distribution of values, starting at zero, along with their sum
+fn cumulative(values: impl Iterator<Item = f32>) -> (Vec<f32>, f32) {
+    let mut cdf = vec![0.0];
+    for value in values {
+        cdf.push(cdf.last().unwrap() + value);
+    }
+
+    let sum = *cdf.last().unwrap();
+    if sum > 0.0 {
+        cdf.iter_mut().for_each(|c| *c /= sum);
+    }
+
+    (cdf, sum)
+}
+
+// Picks a bin of a cumulative distribution, returns it and the continuous position in bins
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    let bins = cdf.len() - 1;
+    let bin = (cdf.partition_point(|&c| c <= u).max(1) - 1).min(bins - 1);
+    let (start, end) = (cdf[bin], cdf[bin + 1]);
+    let t = if end > start {
+        (u - start) / (end - start)
+    } else {
+        0.5
+    };
+
+    (bin, bin as f32 + t)
+}
@@ -0,0 +1,54 @@
+mod aperture;
+mod orthographic;
+mod panoramic;
+mod perspective;
+mod realistic;
+mod stereo;
+
+pub use aperture::*;
+pub use orthographic::*;
+pub use panoramic::*;
+pub use perspective::*;
+pub use realistic::*;
+pub use stereo::*;
+
+use crate::{sampler::Sampler, Ray};
+use glam::Vec3;
+
+/// Turns positions on the image into rays leaving the camera
+pub trait Camera: std::fmt::Debug + Send + Sync {
+    /// The ray through the point s, t on the image, both in [0, 1] from the bottom left corner.
+    /// None where the camera does not see anything, like outside the circle of a fisheye.
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray>;
+
+    /// The ray through s, t along with how much of the light along it reaches the film, for
+    /// cameras where that changes over the image
+    fn weighted_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        self.ray(s, t, sampler).map(|ray| (ray, 1.0))
+    }
+
+    /// How far in front of the camera a point is, written out as the depth pass
+    fn depth(&self, point: Vec3) -> f32;
+
+    /// How many separate images the camera lays out across and down the film, like the eyes of
+    /// a stereo camera. Pixel filters should not reach from one into the next.
+    fn grid(&self) -> (u32, u32) {
+        (1, 1)
+    }
+}
+
+/// Builds the orthonormal frame of a camera at origin looking at target.
+/// Returns (right, up, backward), the camera looks down the negative of the last one.
+pub fn look_at(origin: Vec3, target: Vec3, up: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (origin - target).normalize();
+    let u = up.cross(w).normalize();
+    let v = w.cross(u);
+
+    (u, v, w)
+}
+
+/// Picks a point in time while the shutter is open
+pub fn sample_time(shutter: (f32, f32), sampler: &mut dyn Sampler) -> f32 {
+    let (open, close) = shutter;
+    open + sampler.next_1d() * (close - open)
+}
@@ -0,0 +1,61 @@
+use crate::{
+    camera::{look_at, sample_time, Camera},
+    sampler::Sampler,
+    Ray,
+};
+use glam::Vec3;
+
+/// A camera where all rays travel in parallel, so objects keep their size however far away they
+/// are. Rays start on the plane through the origin facing the target, which is view_width wide.
+#[derive(Debug)]
+pub struct OrthographicCamera {
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    /// The direction all rays travel in
+    direction: Vec3,
+    origin: Vec3,
+    /// Interval of time the shutter is open, rays are spread evenly over it
+    shutter: (f32, f32),
+}
+
+impl OrthographicCamera {
+    pub fn new(origin: Vec3, target: Vec3, up: Vec3, view_width: f32, aspect: f32) -> Self {
+        let (u, v, w) = look_at(origin, target, up);
+        let horizontal = view_width * u;
+        let vertical = view_width / aspect * v;
+        let lower_left_corner = origin - 0.5 * horizontal - 0.5 * vertical;
+
+        Self {
+            lower_left_corner,
+            horizontal,
+            vertical,
+            direction: -w,
+            origin,
+            shutter: (0.0, 0.0),
+        }
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let time = sample_time(self.shutter, sampler);
+
+        let ray = Ray::new(
+            self.lower_left_corner + s * self.horizontal + t * self.vertical,
+            self.direction,
+        )
+        .at_time(time);
+
+        Some(ray)
+    }
+
+    fn depth(&self, point: Vec3) -> f32 {
+        (point - self.origin).dot(self.direction)
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{
+    camera::{look_at, sample_time, Camera},
+    sampler::Sampler,
+    Ray,
+};
+use glam::{vec3, Vec3};
+use serde::Deserialize;
+use std::f32::consts::PI;
+
+/// The orientation of a camera, and the interval of time its shutter is open
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    origin: Vec3,
+    right: Vec3,
+    up: Vec3,
+    forward: Vec3,
+    shutter: (f32, f32),
+}
+
+impl Frame {
+    fn new(origin: Vec3, target: Vec3, up: Vec3) -> Self {
+        let (u, v, w) = look_at(origin, target, up);
+
+        Self {
+            origin,
+            right: u,
+            up: v,
+            forward: -w,
+            shutter: (0.0, 0.0),
+        }
+    }
+
+    // A ray from the origin in a direction given in camera space
+    fn ray(&self, direction: Vec3, sampler: &mut dyn Sampler) -> Ray {
+        let direction =
+            direction.x() * self.right + direction.y() * self.up + direction.z() * self.forward;
+
+        Ray::new(self.origin, direction).at_time(sample_time(self.shutter, sampler))
+    }
+}
+
+/// Maps longitude and latitude around the camera onto the image, which should be twice as wide
+/// as it is tall to cover the full sphere. The target is in the center of the image.
+#[derive(Debug)]
+pub struct EquirectangularCamera {
+    frame: Frame,
+}
+
+impl EquirectangularCamera {
+    pub fn new(origin: Vec3, target: Vec3, up: Vec3) -> Self {
+        Self {
+            frame: Frame::new(origin, target, up),
+        }
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.frame.shutter = (open, close);
+        self
+    }
+
+    /// The direction in camera space that s, t on the image looks in
+    pub fn direction(s: f32, t: f32) -> Vec3 {
+        let longitude = (s - 0.5) * 2.0 * PI;
+        let latitude = (t - 0.5) * PI;
+
+        vec3(
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+            latitude.cos() * longitude.cos(),
+        )
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        Some(self.frame.ray(Self::direction(s, t), sampler))
+    }
+
+    fn depth(&self, point: Vec3) -> f32 {
+        (point - self.frame.origin).length()
+    }
+}
+
+/// How a fisheye lens maps the angle away from its axis to the distance from the image center
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FisheyeMapping {
+    /// Distance grows linearly with the angle
+    Equidistant,
+    /// Areas on the image are proportional to solid angles
+    Equisolid,
+}
+
+impl Default for FisheyeMapping {
+    fn default() -> Self {
+        FisheyeMapping::Equidistant
+    }
+}
+
+/// A fisheye lens covering fov degrees across the circle inscribed in the height of the image.
+/// Everything outside the circle stays black.
+#[derive(Debug)]
+pub struct FisheyeCamera {
+    frame: Frame,
+    mapping: FisheyeMapping,
+    /// Field of view in radians
+    fov: f32,
+    aspect: f32,
+}
+
+impl FisheyeCamera {
+    pub fn new(
+        origin: Vec3,
+        target: Vec3,
+        up: Vec3,
+        mapping: FisheyeMapping,
+        fov: f32,
+        aspect: f32,
+    ) -> Self {
+        Self {
+            frame: Frame::new(origin, target, up),
+            mapping,
+            fov: fov.to_radians(),
+            aspect,
+        }
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.frame.shutter = (open, close);
+        self
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let x = (2.0 * s - 1.0) * self.aspect;
+        let y = 2.0 * t - 1.0;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+
+        // Angle away from the axis of the lens
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * self.fov / 2.0,
+            FisheyeMapping::Equisolid => 2.0 * (r * (self.fov / 4.0).sin()).min(1.0).asin(),
+        };
+        let phi = y.atan2(x);
+        let direction = vec3(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+
+        Some(self.frame.ray(direction, sampler))
+    }
+
+    fn depth(&self, point: Vec3) -> f32 {
+        (point - self.frame.origin).length()
+    }
+}
+
+/// Renders the six faces of a cube map around the origin, each a 90 degree view along a world
+/// axis. The faces are laid out in a 3 by 2 grid, +X, -X, +Y on the top row and -Y, +Z, -Z on the
+/// bottom, oriented like OpenGL cube map faces. The image should be 3:2 for square faces.
+#[derive(Debug)]
+pub struct CubeMapCamera {
+    origin: Vec3,
+    shutter: (f32, f32),
+}
+
+impl CubeMapCamera {
+    pub fn new(origin: Vec3) -> Self {
+        Self {
+            origin,
+            shutter: (0.0, 0.0),
+        }
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+}
+
+impl Camera for CubeMapCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let column = ((s * 3.0) as usize).min(2);
+        let row = 1 - ((t * 2.0) as usize).min(1);
+
+        // Coordinates on the face in [-1, 1], with tc growing downwards
+        let sc = 2.0 * (s * 3.0 - column as f32) - 1.0;
+        let tc = 1.0 - 2.0 * (t * 2.0 - (1 - row) as f32);
+
+        let direction = match row * 3 + column {
+            0 => vec3(1.0, -tc, -sc),
+            1 => vec3(-1.0, -tc, sc),
+            2 => vec3(sc, 1.0, tc),
+            3 => vec3(sc, -1.0, -tc),
+            4 => vec3(sc, -tc, 1.0),
+            _ => vec3(-sc, -tc, -1.0),
+        };
+
+        Some(Ray::new(self.origin, direction).at_time(sample_time(self.shutter, sampler)))
+    }
+
+    fn depth(&self, point: Vec3) -> f32 {
+        (point - self.origin).length()
+    }
+
+    fn grid(&self) -> (u32, u32) {
+        (3, 2)
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{
+    camera::{look_at, sample_time, Aperture, Camera},
+    sampler::Sampler,
+    Ray,
+};
+use glam::Vec3;
+use std::f32::consts::PI;
+
+/// A pinhole camera, or a thin lens one with depth of field when given a lens
+#[derive(Debug)]
+pub struct PerspectiveCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    /// Half the width and height of the image plane one unit in front of the camera
+    half_width: f32,
+    half_height: f32,
+    /// Sideways offset of the image from the axis of the camera, on the same plane
+    shift: f32,
+    lens_radius: f32,
+    /// Distance to the plane that is in perfect focus
+    focus_distance: f32,
+    aperture: Aperture,
+    /// Interval of time the shutter is open, rays are spread evenly over it
+    shutter: (f32, f32),
+}
+
+impl PerspectiveCamera {
+    pub fn new(origin: Vec3, target: Vec3, up: Vec3, vfov: f32, aspect: f32) -> Self {
+        let theta = vfov * PI / 180.0;
+        let half_height = f32::tan(theta / 2.0);
+        let half_width = aspect * half_height;
+        let (u, v, w) = look_at(origin, target, up);
+
+        Self {
+            origin,
+            u,
+            v,
+            w,
+            half_width,
+            half_height,
+            shift: 0.0,
+            lens_radius: 0.0,
+            focus_distance: (origin - target).length(),
+            aperture: Aperture::Circle,
+            shutter: (0.0, 0.0),
+        }
+    }
+
+    /// Gives the camera a lens with an aperture of the given diameter, focused on the plane
+    /// focus_distance in front of it
+    pub fn with_lens(mut self, diameter: f32, focus_distance: f32, aperture: Aperture) -> Self {
+        self.lens_radius = diameter / 2.0;
+        self.focus_distance = focus_distance;
+        self.aperture = aperture;
+        self
+    }
+
+    /// Moves the image to the right of the axis of the camera by shift, measured on the image
+    /// plane one unit in front of it, without turning the camera
+    pub fn with_shift(mut self, shift: f32) -> Self {
+        self.shift = shift;
+        self
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (lens_x, lens_y) = self.aperture.sample(sampler);
+        let offset = self.lens_radius * (lens_x * self.u + lens_y * self.v);
+        let time = sample_time(self.shutter, sampler);
+
+        // Every ray through the same point on the image meets on the focus plane
+        let focus = self.origin
+            + self.focus_distance
+                * (((2.0 * s - 1.0) * self.half_width + self.shift) * self.u
+                    + (2.0 * t - 1.0) * self.half_height * self.v
+                    - self.w);
+        let ray = Ray::new(self.origin + offset, focus - self.origin - offset).at_time(time);
+
+        Some(ray)
+    }
+
+    fn depth(&self, point: Vec3) -> f32 {
+        (self.origin - point).dot(self.w)
+    }
+}
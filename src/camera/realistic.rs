@@ -0,0 +1,451 @@
+use crate::{
+    camera::{look_at, sample_time, Camera},
+    sampler::Sampler,
+    Ray,
+};
+use anyhow::{anyhow, bail, ensure};
+use glam::{vec3, Vec3};
+
+/// One spherical surface of a lens system, in meters
+#[derive(Clone, Copy, Debug)]
+struct LensElement {
+    /// Radius of the sphere, positive when its center is towards the film. Zero for a flat stop.
+    curvature_radius: f32,
+    /// Distance along the axis to the next surface towards the film
+    thickness: f32,
+    /// Index of refraction of the glass between this surface and the next
+    eta: f32,
+    aperture_radius: f32,
+}
+
+/// A camera that traces rays through a system of spherical lenses from a lens prescription,
+/// following the realistic camera of "Physically Based Rendering" by Pharr, Jakob and Humphreys.
+/// Vignetting, distortion and focus breathing come from the lenses rather than being modeled.
+///
+/// Lens space has the film at z = 0 and the lenses towards negative z, with the scene beyond them.
+#[derive(Debug)]
+pub struct RealisticCamera {
+    origin: Vec3,
+    right: Vec3,
+    up: Vec3,
+    forward: Vec3,
+    /// Surfaces from the one facing the scene to the one facing the film
+    elements: Vec<LensElement>,
+    /// Physical size of the film, in meters
+    film_width: f32,
+    film_height: f32,
+    /// Boxes around the exit pupil on the rear element, seen from points along the film x axis
+    pupil_bounds: Vec<PupilBounds>,
+    /// Light reaching the center of the film, which the rest of the film is exposed relative to
+    exposure: f32,
+    shutter: (f32, f32),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PupilBounds {
+    min: (f32, f32),
+    max: (f32, f32),
+}
+
+impl PupilBounds {
+    fn area(&self) -> f32 {
+        (self.max.0 - self.min.0).max(0.0) * (self.max.1 - self.min.1).max(0.0)
+    }
+}
+
+impl RealisticCamera {
+    /// Number of distances from the center of the film the exit pupil is bounded at
+    const PUPIL_BINS: usize = 32;
+    /// Points per side of the grid the rear element is probed with when bounding the exit pupil
+    const PUPIL_GRID: usize = 128;
+
+    /// Loads a lens prescription with one surface per line, from the scene to the film: the
+    /// curvature radius, the thickness to the next surface, the index of refraction behind it and
+    /// the aperture diameter, all in millimeters. A radius of zero is the aperture stop.
+    /// Lines starting with # are comments.
+    pub fn load_lenses(path: &str) -> anyhow::Result<Vec<[f32; 4]>> {
+        let text = std::fs::read_to_string(path)?;
+
+        let mut lenses = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let values: Vec<f32> = match line.split_whitespace().map(str::parse).collect() {
+                Ok(values) => values,
+                Err(error) => bail!("Lens file {} line {}: {}", path, number + 1, error),
+            };
+            ensure!(
+                values.len() == 4,
+                "Lens file {} line {} has {} values, expected radius, thickness, ior and aperture",
+                path,
+                number + 1,
+                values.len()
+            );
+            lenses.push([values[0], values[1], values[2], values[3]]);
+        }
+        ensure!(!lenses.is_empty(), "Lens file {} has no lenses", path);
+
+        Ok(lenses)
+    }
+
+    /// A camera at origin looking at target, focused at focus_distance in scene units (meters).
+    /// The film diagonal is in millimeters, and the film has the aspect ratio width / height.
+    /// When given, the f-stop narrows the aperture stop but never widens it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        origin: Vec3,
+        target: Vec3,
+        up: Vec3,
+        lenses: &[[f32; 4]],
+        film_diagonal: f32,
+        aspect: f32,
+        focus_distance: f32,
+        f_stop: Option<f32>,
+    ) -> anyhow::Result<Self> {
+        let (u, v, w) = look_at(origin, target, up);
+        let diagonal = film_diagonal / 1000.0;
+        let film_height = diagonal / (1.0 + aspect * aspect).sqrt();
+
+        let elements = lenses
+            .iter()
+            .map(|&[radius, thickness, eta, aperture]| LensElement {
+                curvature_radius: radius / 1000.0,
+                thickness: thickness / 1000.0,
+                // Air is sometimes written as zero
+                eta: if eta == 0.0 { 1.0 } else { eta },
+                aperture_radius: aperture / 2000.0,
+            })
+            .collect();
+
+        let mut camera = Self {
+            origin,
+            right: u,
+            up: v,
+            forward: -w,
+            elements,
+            film_width: film_height * aspect,
+            film_height,
+            pupil_bounds: Vec::new(),
+            exposure: 1.0,
+            shutter: (0.0, 0.0),
+        };
+
+        let (principal, focal) = camera.cardinal_points()?;
+        if let Some(f_stop) = f_stop {
+            let focal_length = focal[0] - principal[0];
+            let radius = focal_length.abs() / (2.0 * f_stop);
+            for element in camera.elements.iter_mut() {
+                if element.curvature_radius == 0.0 {
+                    element.aperture_radius = element.aperture_radius.min(radius);
+                }
+            }
+        }
+        camera.focus(principal, focal, focus_distance)?;
+
+        // The exit pupil moves with the focus, so it is bounded last
+        camera.pupil_bounds = (0..Self::PUPIL_BINS)
+            .map(|bin| camera.bound_exit_pupil(bin))
+            .collect();
+        camera.exposure = camera.pupil_bounds[0].area() * camera.pupil_transmission(0);
+        ensure!(
+            camera.exposure > 0.0,
+            "No light makes it through the lenses to the center of the film"
+        );
+
+        Ok(camera)
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+
+    fn rear_z(&self) -> f32 {
+        self.elements.last().unwrap().thickness
+    }
+
+    fn front_z(&self) -> f32 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear_radius(&self) -> f32 {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    fn film_diagonal(&self) -> f32 {
+        (self.film_width * self.film_width + self.film_height * self.film_height).sqrt()
+    }
+
+    /// Traces a ray in camera space, starting at the film, out through the lenses.
+    /// None if it is blocked by the edge of an element or totally internally reflected.
+    fn trace_from_film(&self, origin: Vec3, direction: Vec3) -> Option<(Vec3, Vec3)> {
+        let (mut o, mut d) = (flip(origin), flip(direction));
+
+        let mut element_z = 0.0;
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            element_z -= element.thickness;
+
+            let (t, normal) = intersect_element(element, element_z, o, d)?;
+            o += t * d;
+            if o.x() * o.x() + o.y() * o.y() > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+
+            if let Some(normal) = normal {
+                let eta_t = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+                d = refract(-d.normalize(), normal, element.eta / eta_t)?;
+            }
+        }
+
+        Some((flip(o), flip(d)))
+    }
+
+    /// Traces a ray in camera space, starting in front of the lenses, in through them to the film
+    fn trace_from_scene(&self, origin: Vec3, direction: Vec3) -> Option<(Vec3, Vec3)> {
+        let (mut o, mut d) = (flip(origin), flip(direction));
+
+        let mut element_z = -self.front_z();
+        for (i, element) in self.elements.iter().enumerate() {
+            let (t, normal) = intersect_element(element, element_z, o, d)?;
+            o += t * d;
+            if o.x() * o.x() + o.y() * o.y() > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+
+            if let Some(normal) = normal {
+                let eta_i = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+                d = refract(-d.normalize(), normal, eta_i / element.eta)?;
+            }
+            element_z += element.thickness;
+        }
+
+        Some((flip(o), flip(d)))
+    }
+
+    /// Principal planes and focal points of the thick lens the system acts like, found by tracing
+    /// rays parallel to the axis through it from both sides. They are given along the axis in lens
+    /// space, and the first of each is on the film side.
+    fn cardinal_points(&self) -> anyhow::Result<([f32; 2], [f32; 2])> {
+        let height = 0.001 * self.film_diagonal();
+        let cardinal = |start: Vec3, (o, d): (Vec3, Vec3)| {
+            let focal = -(o.z() + d.z() * -o.x() / d.x());
+            let principal = -(o.z() + d.z() * (start.x() - o.x()) / d.x());
+            (principal, focal)
+        };
+
+        let start = vec3(height, 0.0, self.front_z() + 1.0);
+        let film_side = self
+            .trace_from_scene(start, vec3(0.0, 0.0, -1.0))
+            .ok_or_else(|| anyhow!("Rays along the axis do not make it through the lenses"))?;
+        let (principal_film, focal_film) = cardinal(start, film_side);
+
+        let start = vec3(height, 0.0, self.rear_z() - 1.0);
+        let scene_side = self
+            .trace_from_film(start, vec3(0.0, 0.0, 1.0))
+            .ok_or_else(|| anyhow!("Rays along the axis do not make it out of the lenses"))?;
+        let (principal_scene, focal_scene) = cardinal(start, scene_side);
+
+        Ok(([principal_film, principal_scene], [focal_film, focal_scene]))
+    }
+
+    /// Moves the film so points at focus_distance in front of it are sharp, using the thick lens
+    /// approximation
+    fn focus(&mut self, principal: [f32; 2], focal: [f32; 2], distance: f32) -> anyhow::Result<()> {
+        let f = focal[0] - principal[0];
+        let z = -distance;
+        let c = (principal[1] - z - principal[0]) * (principal[1] - z - 4.0 * f - principal[0]);
+        ensure!(
+            c > 0.0,
+            "The lenses can not focus as close as {}, try a longer focus distance",
+            distance
+        );
+
+        let delta = 0.5 * (principal[1] - z + principal[0] - c.sqrt());
+        let rear = self.elements.last_mut().unwrap();
+        rear.thickness += delta;
+        ensure!(
+            rear.thickness > 0.0,
+            "Focusing at {} would move the film into the lenses",
+            distance
+        );
+
+        Ok(())
+    }
+
+    // Distance from the center of the film to the middle of a bin of the pupil bounds
+    fn bin_distance(&self, bin: usize) -> f32 {
+        (bin as f32 + 0.5) / Self::PUPIL_BINS as f32 * self.film_diagonal() / 2.0
+    }
+
+    // Probes a grid over the rear element from points along the film x axis within a bin, and
+    // bounds the points that make it out of the lenses
+    fn bound_exit_pupil(&self, bin: usize) -> PupilBounds {
+        let size = 1.5 * self.rear_radius();
+        let step = 2.0 * size / Self::PUPIL_GRID as f32;
+        let bin_width = self.film_diagonal() / 2.0 / Self::PUPIL_BINS as f32;
+        let x0 = bin as f32 * bin_width;
+
+        let mut bounds = PupilBounds {
+            min: (f32::INFINITY, f32::INFINITY),
+            max: (f32::NEG_INFINITY, f32::NEG_INFINITY),
+        };
+        for j in 0..Self::PUPIL_GRID {
+            for i in 0..Self::PUPIL_GRID {
+                let (x, y) = (
+                    -size + (i as f32 + 0.5) * step,
+                    -size + (j as f32 + 0.5) * step,
+                );
+                // Spread the film points over the bin without a random number generator
+                let film_x = x0 + ((i * 7 + j * 13) % 16) as f32 / 16.0 * bin_width;
+
+                let film = vec3(film_x, 0.0, 0.0);
+                let rear = vec3(x, y, self.rear_z());
+                if self.trace_from_film(film, rear - film).is_some() {
+                    bounds.min = (bounds.min.0.min(x), bounds.min.1.min(y));
+                    bounds.max = (bounds.max.0.max(x), bounds.max.1.max(y));
+                }
+            }
+        }
+
+        // Grow the bounds by a grid cell, as points between the probes may get through too
+        if bounds.min.0 <= bounds.max.0 {
+            bounds.min = (bounds.min.0 - step, bounds.min.1 - step);
+            bounds.max = (bounds.max.0 + step, bounds.max.1 + step);
+        }
+
+        bounds
+    }
+
+    // Fraction of the points within the pupil bounds of a bin that make it out of the lenses,
+    // from the middle of the bin
+    fn pupil_transmission(&self, bin: usize) -> f32 {
+        let bounds = self.pupil_bounds[bin];
+        let film = vec3(self.bin_distance(bin), 0.0, 0.0);
+        let n = Self::PUPIL_GRID;
+
+        let mut through = 0;
+        for j in 0..n {
+            for i in 0..n {
+                let x = bounds.min.0 + (i as f32 + 0.5) / n as f32 * (bounds.max.0 - bounds.min.0);
+                let y = bounds.min.1 + (j as f32 + 0.5) / n as f32 * (bounds.max.1 - bounds.min.1);
+                let rear = vec3(x, y, self.rear_z());
+                if self.trace_from_film(film, rear - film).is_some() {
+                    through += 1;
+                }
+            }
+        }
+
+        through as f32 / (n * n) as f32
+    }
+}
+
+impl Camera for RealisticCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        self.weighted_ray(s, t, sampler).map(|(ray, _)| ray)
+    }
+
+    fn weighted_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        // The lenses flip the image, so the film is flipped to keep it upright
+        let film = vec3(
+            -(s - 0.5) * self.film_width,
+            -(t - 0.5) * self.film_height,
+            0.0,
+        );
+
+        // Sample the bounds of the exit pupil seen from this distance, rotated to the film point
+        let distance = (film.x() * film.x() + film.y() * film.y()).sqrt();
+        let bin = ((distance / (self.film_diagonal() / 2.0) * Self::PUPIL_BINS as f32) as usize)
+            .min(Self::PUPIL_BINS - 1);
+        let bounds = self.pupil_bounds[bin];
+        let (u1, u2) = sampler.next_2d();
+        let x = bounds.min.0 + u1 * (bounds.max.0 - bounds.min.0);
+        let y = bounds.min.1 + u2 * (bounds.max.1 - bounds.min.1);
+        let (sin, cos) = if distance > 0.0 {
+            (film.y() / distance, film.x() / distance)
+        } else {
+            (0.0, 1.0)
+        };
+        let rear = vec3(cos * x - sin * y, sin * x + cos * y, self.rear_z());
+
+        let time = sample_time(self.shutter, sampler);
+        let (o, d) = self.trace_from_film(film, rear - film)?;
+
+        // Light falls off with the fourth power of the cosine towards the film, and is spread
+        // over the area of the pupil bounds it was sampled from
+        let cos_theta = (rear - film).normalize().z();
+        let weight = cos_theta.powi(4) * bounds.area() / self.exposure;
+
+        let to_world = |v: Vec3| v.x() * self.right + v.y() * self.up + v.z() * self.forward;
+        let ray = Ray::new(self.origin + to_world(o), to_world(d)).at_time(time);
+
+        Some((ray, weight))
+    }
+
+    fn depth(&self, point: Vec3) -> f32 {
+        (point - self.origin).dot(self.forward)
+    }
+}
+
+// Mirrors between camera space, where the scene is towards positive z, and lens space
+fn flip(v: Vec3) -> Vec3 {
+    vec3(v.x(), v.y(), -v.z())
+}
+
+/// Intersects a ray in lens space with a lens surface whose vertex is at z on the axis.
+/// The normal faces against the ray, and is None for the flat aperture stop.
+fn intersect_element(
+    element: &LensElement,
+    z: f32,
+    origin: Vec3,
+    direction: Vec3,
+) -> Option<(f32, Option<Vec3>)> {
+    let radius = element.curvature_radius;
+    if radius == 0.0 {
+        let t = (z - origin.z()) / direction.z();
+        return if t > 0.0 { Some((t, None)) } else { None };
+    }
+
+    let o = origin - vec3(0.0, 0.0, z + radius);
+    let a = direction.length_squared();
+    let b = 2.0 * direction.dot(o);
+    let c = o.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (t0, t1) = ((-b - root) / (2.0 * a), (-b + root) / (2.0 * a));
+
+    // Of the two intersections, the surface is the one on the side of the vertex
+    let closer = (direction.z() > 0.0) ^ (radius < 0.0);
+    let t = if closer { t0.min(t1) } else { t0.max(t1) };
+    if t < 0.0 {
+        return None;
+    }
+
+    let normal = (o + t * direction).normalize();
+    let normal = if normal.dot(direction) > 0.0 {
+        -normal
+    } else {
+        normal
+    };
+
+    Some((t, Some(normal)))
+}
+
+/// Refracts the direction wi pointing away from the surface, where eta is the index of refraction
+/// on its side over the one on the other side. None on total internal reflection.
+fn refract(wi: Vec3, normal: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = normal.dot(wi);
+    let sin2_i = (1.0 - cos_i * cos_i).max(0.0);
+    let sin2_t = eta * eta * sin2_i;
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+
+    Some(eta * -wi + (eta * cos_i - cos_t) * normal)
+}
@@ -0,0 +1,151 @@
+use crate::{
+    camera::{look_at, sample_time, Camera, EquirectangularCamera},
+    sampler::Sampler,
+    Ray,
+};
+use glam::Vec3;
+use serde::Deserialize;
+
+/// How the images of the two eyes share the film
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StereoLayout {
+    /// Left eye on the left half, right eye on the right half
+    SideBySide,
+    /// Left eye on the top half, right eye on the bottom half
+    TopBottom,
+}
+
+impl Default for StereoLayout {
+    fn default() -> Self {
+        StereoLayout::SideBySide
+    }
+}
+
+/// Renders a left and a right eye next to each other on the same film
+#[derive(Debug)]
+pub struct StereoCamera {
+    left: Box<dyn Camera>,
+    right: Box<dyn Camera>,
+    layout: StereoLayout,
+}
+
+impl StereoCamera {
+    pub fn new(left: Box<dyn Camera>, right: Box<dyn Camera>, layout: StereoLayout) -> Self {
+        Self {
+            left,
+            right,
+            layout,
+        }
+    }
+
+    /// Aspect ratio of the image of each eye, for a film with the aspect ratio given
+    pub fn eye_aspect(aspect: f32, layout: StereoLayout) -> f32 {
+        match layout {
+            StereoLayout::SideBySide => aspect / 2.0,
+            StereoLayout::TopBottom => aspect * 2.0,
+        }
+    }
+}
+
+impl Camera for StereoCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.ray(2.0 * s, t, sampler),
+            StereoLayout::SideBySide => self.right.ray(2.0 * s - 1.0, t, sampler),
+            StereoLayout::TopBottom if t >= 0.5 => self.left.ray(s, 2.0 * t - 1.0, sampler),
+            StereoLayout::TopBottom => self.right.ray(s, 2.0 * t, sampler),
+        }
+    }
+
+    fn weighted_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.weighted_ray(2.0 * s, t, sampler),
+            StereoLayout::SideBySide => self.right.weighted_ray(2.0 * s - 1.0, t, sampler),
+            StereoLayout::TopBottom if t >= 0.5 => {
+                self.left.weighted_ray(s, 2.0 * t - 1.0, sampler)
+            }
+            StereoLayout::TopBottom => self.right.weighted_ray(s, 2.0 * t, sampler),
+        }
+    }
+
+    /// Depth as seen from the left eye, the eyes are too close together for it to matter
+    fn depth(&self, point: Vec3) -> f32 {
+        self.left.depth(point)
+    }
+
+    fn grid(&self) -> (u32, u32) {
+        let (columns, rows) = self.left.grid();
+        match self.layout {
+            StereoLayout::SideBySide => (2 * columns, rows),
+            StereoLayout::TopBottom => (columns, 2 * rows),
+        }
+    }
+}
+
+/// One eye of an omni-directional stereo panorama, from "Rendering Omni-directional Stereo
+/// Content" by Google. Every column of the equirectangular image is seen from its own point on
+/// a circle as wide as the eyes are apart, so the stereo holds up whichever way the viewer turns.
+#[derive(Debug)]
+pub struct OdsCamera {
+    origin: Vec3,
+    right: Vec3,
+    up: Vec3,
+    forward: Vec3,
+    /// Signed distance of the eye from the center, negative for the left eye
+    offset: f32,
+    /// Distance at which the eyes look at the same point, parallel if missing
+    convergence: Option<f32>,
+    shutter: (f32, f32),
+}
+
+impl OdsCamera {
+    pub fn new(
+        origin: Vec3,
+        target: Vec3,
+        up: Vec3,
+        offset: f32,
+        convergence: Option<f32>,
+    ) -> Self {
+        let (u, v, w) = look_at(origin, target, up);
+
+        Self {
+            origin,
+            right: u,
+            up: v,
+            forward: -w,
+            offset,
+            convergence,
+            shutter: (0.0, 0.0),
+        }
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = (open, close);
+        self
+    }
+}
+
+impl Camera for OdsCamera {
+    fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let direction = EquirectangularCamera::direction(s, t);
+
+        // The eye sits to the side of the horizontal direction it looks in
+        let longitude = (s - 0.5) * 2.0 * std::f32::consts::PI;
+        let eye = self.offset * Vec3::new(longitude.cos(), 0.0, -longitude.sin());
+        let direction = match self.convergence {
+            Some(distance) => (direction * distance - eye).normalize(),
+            None => direction,
+        };
+
+        let to_world = |v: Vec3| v.x() * self.right + v.y() * self.up + v.z() * self.forward;
+        let ray = Ray::new(self.origin + to_world(eye), to_world(direction))
+            .at_time(sample_time(self.shutter, sampler));
+
+        Some(ray)
+    }
+
+    fn depth(&self, point: Vec3) -> f32 {
+        (point - self.origin).length()
+    }
+}
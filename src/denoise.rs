@@ -0,0 +1,134 @@
+use crate::{
+    aov::Aov,
+    film::{luminance, Film},
+    DenoiseConfig,
+};
+use glam::Vec3;
+use rayon::prelude::*;
+
+/// B3 spline weights of the 5x5 kernel, spread further apart every iteration
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Smallest albedo that is divided out, so black surfaces keep their noise
+const MIN_ALBEDO: f32 = 0.01;
+
+/// Edge-avoiding à-trous wavelet filter, from "Edge-Avoiding À-Trous Wavelet Transform for fast
+/// Global Illumination Filtering" by Dammertz et al., with the variance guided color weights of
+/// "Spatiotemporal Variance-Guided Filtering" by Schied et al.
+/// Neighbours are weighted down where albedo, normal or depth change, when the film has them,
+/// and where colors differ by more than the noise of the pixel would explain.
+pub fn denoise(film: &Film, config: DenoiseConfig) -> Vec<Vec3> {
+    let width = film.width() as usize;
+    let height = film.height() as usize;
+
+    let albedo = film.aov(Aov::Albedo);
+    let normal = film.aov(Aov::Normal);
+    let depth = film.aov(Aov::Depth);
+
+    // Filter the lighting without the surface colors, so textures stay sharp
+    let demodulate = |color: Vec3, i: usize| match &albedo {
+        Some(albedo) => color / albedo[i].max(Vec3::splat(MIN_ALBEDO)),
+        None => color,
+    };
+    let mut colors: Vec<_> = film
+        .pixels()
+        .iter()
+        .enumerate()
+        .map(|(i, pixel)| demodulate(pixel.color(), i))
+        .collect();
+    let mut variances: Vec<_> = film
+        .pixels()
+        .iter()
+        .enumerate()
+        .map(|(i, pixel)| {
+            let scale = luminance(demodulate(Vec3::one(), i));
+            pixel.variance() * scale * scale
+        })
+        .collect();
+
+    for iteration in 0..config.iterations {
+        let step = 1isize << iteration;
+
+        let (next_colors, next_variances): (Vec<_>, Vec<_>) = (0..width * height)
+            .into_par_iter()
+            .map(|p| {
+                // Pixels outside of a render region have no samples, and are left out
+                if film.pixels()[p].samples == 0 {
+                    return (Vec3::zero(), 0.0);
+                }
+
+                let (px, py) = ((p % width) as isize, (p / width) as isize);
+                let color_p = colors[p];
+                let luminance_p = luminance(color_p);
+                let deviation_p = variances[p].sqrt();
+
+                let mut color_sum = Vec3::zero();
+                let mut variance_sum = 0.0;
+                let mut weight_sum = 0.0;
+                for (ky, &hy) in KERNEL.iter().enumerate() {
+                    for (kx, &hx) in KERNEL.iter().enumerate() {
+                        let qx = px + (kx as isize - 2) * step;
+                        let qy = py + (ky as isize - 2) * step;
+                        if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+                        if film.pixels()[q].samples == 0 {
+                            continue;
+                        }
+
+                        let mut weight = hx * hy;
+
+                        let luminance_difference = (luminance(colors[q]) - luminance_p).abs();
+                        weight *= (-luminance_difference
+                            / (config.sigma_color * deviation_p + 1e-4))
+                            .exp();
+
+                        if let Some(albedo) = &albedo {
+                            let difference = (albedo[q] - albedo[p]).length_squared();
+                            weight *= (-difference / config.sigma_albedo.powi(2)).exp();
+                        }
+                        // Pixels that only saw the sky have no normal, and are only alike each other
+                        if let Some(normal) = &normal {
+                            weight *= match (normal[p] == Vec3::zero(), normal[q] == Vec3::zero()) {
+                                (true, true) => 1.0,
+                                (false, false) => {
+                                    normal[q].dot(normal[p]).max(0.0).powf(config.sigma_normal)
+                                }
+                                _ => 0.0,
+                            };
+                        }
+                        if let Some(depth) = &depth {
+                            let (dp, dq) = (depth[p].x(), depth[q].x());
+                            let difference = (dp - dq).abs() / (dp.max(dq) + 1e-4);
+                            weight *= (-difference / (config.sigma_depth * step as f32)).exp();
+                        }
+
+                        color_sum += weight * colors[q];
+                        variance_sum += weight * weight * variances[q];
+                        weight_sum += weight;
+                    }
+                }
+
+                // The center tap has a weight of one before the kernel, so this stays positive
+                (
+                    color_sum / weight_sum,
+                    variance_sum / (weight_sum * weight_sum),
+                )
+            })
+            .unzip();
+
+        colors = next_colors;
+        variances = next_variances;
+    }
+
+    // Put the surface colors back
+    match &albedo {
+        Some(albedo) => colors
+            .iter()
+            .zip(albedo)
+            .map(|(color, albedo)| *color * albedo.max(Vec3::splat(MIN_ALBEDO)))
+            .collect(),
+        None => colors,
+    }
+}
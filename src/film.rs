@@ -0,0 +1,471 @@
+use crate::{
+    aov::{save_pfm, Aov, AovPixel},
+    scene::Image,
+    tile::{Tile, TileOrder},
+};
+use anyhow::{bail, ensure};
+use glam::Vec3;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// Identifies checkpoint files, the last byte is the version of the format
+const CHECKPOINT_MAGIC: [u8; 8] = *b"PTCKPT\0\x03";
+
+/// The accumulated samples of a single pixel
+#[derive(Clone, Copy, Debug)]
+pub struct FilmPixel {
+    /// Sum of all samples
+    pub sum: Vec3,
+    /// Sum of every other sample, half of the samples used to estimate the error
+    pub half_sum: Vec3,
+    /// Sum of the squared luminance of all samples, for the variance
+    pub sum_squares: f32,
+    /// Sum of how much of the pixel every sample covered
+    pub alpha_sum: f32,
+    pub samples: u32,
+    /// Whether the pixel still needs more samples
+    pub active: bool,
+}
+
+impl Default for FilmPixel {
+    fn default() -> Self {
+        Self {
+            sum: Vec3::zero(),
+            half_sum: Vec3::zero(),
+            sum_squares: 0.0,
+            alpha_sum: 0.0,
+            samples: 0,
+            active: true,
+        }
+    }
+}
+
+impl FilmPixel {
+    pub fn add_sample(&mut self, color: Vec3, alpha: f32) {
+        if self.samples % 2 == 0 {
+            self.half_sum += color;
+        }
+        self.sum += color;
+        self.sum_squares += luminance(color).powi(2);
+        self.alpha_sum += alpha;
+        self.samples += 1;
+    }
+
+    /// The average of all samples
+    pub fn color(&self) -> Vec3 {
+        if self.samples == 0 {
+            Vec3::zero()
+        } else {
+            self.sum / self.samples as f32
+        }
+    }
+
+    /// How much of the pixel is covered, zero where only holes and transparent sky were seen
+    pub fn alpha(&self) -> f32 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            (self.alpha_sum / self.samples as f32).clamp(0.0, 1.0)
+        }
+    }
+
+    /// Variance of the luminance of the pixel, as an estimate of the mean of its samples
+    pub fn variance(&self) -> f32 {
+        if self.samples < 2 {
+            return 0.0;
+        }
+
+        let n = self.samples as f32;
+        let mean = luminance(self.color());
+        (self.sum_squares / n - mean * mean).max(0.0) / (n - 1.0)
+    }
+
+    /// Estimates the error of the pixel by comparing the average of all samples with the average
+    /// of half of them, relative to the brightness of the pixel.
+    /// From "A Hierarchical Automatic Stopping Condition for Monte Carlo Global Illumination"
+    /// by Dammertz et al.
+    pub fn error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+
+        let all = self.color();
+        let half = self.half_sum / ((self.samples + 1) / 2) as f32;
+        let difference = (all - half).abs();
+        let brightness = (all.x() + all.y() + all.z()).sqrt();
+
+        if brightness > 0.0 {
+            (difference.x() + difference.y() + difference.z()) / brightness
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A rectangle of pixels, from the top left corner of the film
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+}
+
+/// A floating point image that samples are accumulated into.
+/// Pixels are stored row by row from the top of the image.
+pub struct Film {
+    width: u32,
+    height: u32,
+    /// The pixels that are sampled, the rest stay black
+    region: Region,
+    /// Number of passes that have added samples to the film
+    pub passes: u32,
+    pixels: Vec<FilmPixel>,
+    /// Auxiliary values of every pixel, empty unless enabled
+    aovs: Vec<AovPixel>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            region: Region {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            passes: 0,
+            pixels: vec![FilmPixel::default(); (width * height) as usize],
+            aovs: Vec::new(),
+        }
+    }
+
+    /// Starts accumulating auxiliary values next to the colors.
+    /// They are not part of checkpoints, so a resumed film only has them for the new samples.
+    pub fn enable_aovs(&mut self) {
+        self.aovs = vec![AovPixel::default(); self.pixels.len()];
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Only samples the pixels within region, cut short by the edges of the film
+    pub fn set_region(&mut self, region: Region) {
+        let x = region.x.min(self.width);
+        let y = region.y.min(self.height);
+        self.region = Region {
+            x,
+            y,
+            width: region.width.min(self.width - x),
+            height: region.height.min(self.height - y),
+        };
+    }
+
+    /// A film of just the pixels within the region, with their samples and auxiliary values
+    pub fn crop(&self) -> Film {
+        let Region {
+            x,
+            y,
+            width,
+            height,
+        } = self.region;
+        let rows = (y..y + height).map(|row| (row * self.width + x) as usize);
+
+        let mut film = Film::new(width, height);
+        film.passes = self.passes;
+        film.pixels = rows
+            .clone()
+            .flat_map(|start| self.pixels[start..start + width as usize].iter().copied())
+            .collect();
+        if !self.aovs.is_empty() {
+            film.aovs = rows
+                .flat_map(|start| self.aovs[start..start + width as usize].iter().copied())
+                .collect();
+        }
+
+        film
+    }
+
+    pub fn pixels(&self) -> &[FilmPixel] {
+        &self.pixels
+    }
+
+    /// Splits the film into square tiles of size pixels, sorted in order.
+    /// Tiles along the right and bottom edges are cut short by the edges of the film.
+    pub fn tiles_mut(&mut self, size: u32, order: TileOrder) -> Vec<Tile<'_>> {
+        let size = size.max(1);
+        let columns = (self.width + size - 1) / size;
+        let rows = (self.height + size - 1) / size;
+
+        let mut tiles: Vec<_> = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| Tile::new(column * size, row * size)))
+            .collect();
+        let width = self.width as usize;
+        let mut aov_rows = self.aovs.chunks_mut(width);
+        for (y, mut pixels) in self.pixels.chunks_mut(width).enumerate() {
+            let row = y as u32 / size;
+            let mut aovs = aov_rows.next();
+            for column in 0..columns {
+                let split = (size as usize).min(pixels.len());
+                let (tile_row, rest) = pixels.split_at_mut(split);
+                let (tile_aovs, rest_aovs) = match aovs {
+                    Some(aovs) => {
+                        let (tile_aovs, rest_aovs) = aovs.split_at_mut(split);
+                        (Some(tile_aovs), Some(rest_aovs))
+                    }
+                    None => (None, None),
+                };
+                tiles[(row * columns + column) as usize].push_row(tile_row, tile_aovs);
+                pixels = rest;
+                aovs = rest_aovs;
+            }
+        }
+
+        let mut tiles: Vec<_> = tiles.into_iter().map(Some).collect();
+        order
+            .order(columns, rows)
+            .into_iter()
+            .filter_map(|(column, row)| tiles[(row * columns + column) as usize].take())
+            .collect()
+    }
+
+    /// Total number of samples taken over all pixels
+    pub fn samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.samples as u64).sum()
+    }
+
+    /// Only samples the pixels that have fewer than samples so far.
+    /// Returns the number of pixels that are active.
+    pub fn activate_below(&mut self, samples: u32) -> usize {
+        self.activate(|p| p.samples < samples)
+    }
+
+    /// Stops sampling pixels whose error is below threshold or that have reached max_samples.
+    /// Returns the number of pixels that are still active.
+    pub fn update_active(&mut self, threshold: f32, max_samples: u32) -> usize {
+        self.activate(|p| p.samples < max_samples && p.error() > threshold)
+    }
+
+    // Activates the pixels within the region that need more samples, returns how many there are
+    fn activate(&mut self, needs_samples: impl Fn(&FilmPixel) -> bool) -> usize {
+        let (width, region) = (self.width, self.region);
+        self.pixels
+            .iter_mut()
+            .enumerate()
+            .map(|(i, p)| {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                p.active = region.contains(x, y) && needs_samples(p);
+                p.active as usize
+            })
+            .sum()
+    }
+
+    /// Gamma corrects and quantizes the film into an image
+    pub fn image(&self, gamma: f32) -> Image {
+        Image::from_colors(&self.colors(), self.width, self.height, gamma)
+    }
+
+    /// The average color of every pixel
+    pub fn colors(&self) -> Vec<Vec3> {
+        self.pixels.iter().map(FilmPixel::color).collect()
+    }
+
+    /// The coverage of every pixel
+    pub fn alphas(&self) -> Vec<f32> {
+        self.pixels.iter().map(FilmPixel::alpha).collect()
+    }
+
+    /// The values of an auxiliary pass for every pixel, if the film has them
+    pub fn aov(&self, aov: Aov) -> Option<Vec<Vec3>> {
+        if self.aovs.is_empty() {
+            None
+        } else {
+            Some(self.aovs.iter().map(|pixel| pixel.value(aov)).collect())
+        }
+    }
+
+    /// Writes every auxiliary pass to a float image named after it behind the prefix, if the film
+    /// has them
+    pub fn save_aovs(&self, prefix: &str) -> anyhow::Result<()> {
+        if self.aovs.is_empty() {
+            return Ok(());
+        }
+
+        for aov in Aov::ALL {
+            let pixels: Vec<_> = self.aovs.iter().map(|pixel| pixel.value(aov)).collect();
+            let path = format!("{}{}.pfm", prefix, aov.name());
+            save_pfm(&path, self.width, self.height, &pixels, aov.is_scalar())?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the film to a checkpoint that rendering can be resumed from.
+    /// The hashes identify the settings and scene the samples belong to.
+    /// Samplers are pure functions of the seed, pixel and sample index, so the sample counts
+    /// are all the sampler state there is.
+    pub fn save_checkpoint(
+        &self,
+        path: &str,
+        settings_hash: u64,
+        scene_hash: u64,
+    ) -> anyhow::Result<()> {
+        // Written to a temporary file first so a preempted write never clobbers the last checkpoint
+        let temporary = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(std::fs::File::create(&temporary)?);
+
+        writer.write_all(&CHECKPOINT_MAGIC)?;
+        writer.write_all(&settings_hash.to_le_bytes())?;
+        writer.write_all(&scene_hash.to_le_bytes())?;
+        writer.write_all(&self.width.to_le_bytes())?;
+        writer.write_all(&self.height.to_le_bytes())?;
+        writer.write_all(&self.passes.to_le_bytes())?;
+        for pixel in &self.pixels {
+            for value in [pixel.sum, pixel.half_sum]
+                .iter()
+                .flat_map(|v| [v.x(), v.y(), v.z()])
+            {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&pixel.sum_squares.to_le_bytes())?;
+            writer.write_all(&pixel.alpha_sum.to_le_bytes())?;
+            writer.write_all(&pixel.samples.to_le_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        std::fs::rename(&temporary, path)?;
+
+        Ok(())
+    }
+
+    /// Loads a film saved with save_checkpoint, making sure it was rendered with the same
+    /// settings and scene
+    pub fn load_checkpoint(
+        path: &str,
+        settings_hash: u64,
+        scene_hash: u64,
+    ) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(std::fs::File::open(path)?);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != CHECKPOINT_MAGIC {
+            bail!(
+                "{} is not a checkpoint or from an unsupported version",
+                path
+            );
+        }
+
+        ensure!(
+            read_u64(&mut reader)? == settings_hash,
+            "Checkpoint {} was rendered with different settings",
+            path
+        );
+        ensure!(
+            read_u64(&mut reader)? == scene_hash,
+            "Checkpoint {} was rendered from a different scene",
+            path
+        );
+
+        let width = read_u32(&mut reader)?;
+        let height = read_u32(&mut reader)?;
+        let passes = read_u32(&mut reader)?;
+        let mut film = Film::new(width, height);
+        film.passes = passes;
+        for pixel in &mut film.pixels {
+            let mut values = [0.0; 6];
+            for value in &mut values {
+                *value = f32::from_bits(read_u32(&mut reader)?);
+            }
+            pixel.sum = Vec3::new(values[0], values[1], values[2]);
+            pixel.half_sum = Vec3::new(values[3], values[4], values[5]);
+            pixel.sum_squares = f32::from_bits(read_u32(&mut reader)?);
+            pixel.alpha_sum = f32::from_bits(read_u32(&mut reader)?);
+            pixel.samples = read_u32(&mut reader)?;
+        }
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        ensure!(rest.is_empty(), "Checkpoint {} has trailing data", path);
+
+        Ok(film)
+    }
+}
+
+/// Relative luminance of a linear color
+pub fn luminance(color: Vec3) -> f32 {
+    0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z()
+}
+
+fn read_u32(reader: &mut impl Read) -> anyhow::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> anyhow::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut film = Film::new(3, 2);
+        film.passes = 4;
+        for (i, pixel) in film.pixels.iter_mut().enumerate() {
+            pixel.sum = Vec3::new(i as f32, 0.5, -1.0);
+            pixel.half_sum = Vec3::splat(i as f32 * 0.25);
+            pixel.sum_squares = 2.0;
+            pixel.alpha_sum = 0.75;
+            pixel.samples = i as u32 + 1;
+        }
+
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        film.save_checkpoint(path, 1, 2).unwrap();
+
+        let loaded = Film::load_checkpoint(path, 1, 2).unwrap();
+        assert_eq!((loaded.width, loaded.height, loaded.passes), (3, 2, 4));
+        for (a, b) in film.pixels.iter().zip(&loaded.pixels) {
+            assert_eq!(a.sum, b.sum);
+            assert_eq!(a.half_sum, b.half_sum);
+            assert_eq!(a.sum_squares, b.sum_squares);
+            assert_eq!(a.alpha_sum, b.alpha_sum);
+            assert_eq!(a.samples, b.samples);
+        }
+
+        // Samples from other settings or another scene must not be mixed in
+        let settings = Film::load_checkpoint(path, 3, 2).err().unwrap();
+        assert!(
+            settings.to_string().contains("different settings"),
+            "{}",
+            settings
+        );
+        let scene = Film::load_checkpoint(path, 1, 3).err().unwrap();
+        assert!(scene.to_string().contains("different scene"), "{}", scene);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Deserialize;
+use std::f32::consts::PI;
+
+/// Pixel reconstruction filters, all separable into the same filter along x and y.
+/// Radius is how far from the center of the pixel the filter reaches, in pixels.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Filter {
+    Box {
+        #[serde(default = "half")]
+        radius: f32,
+    },
+    Tent {
+        #[serde(default = "one")]
+        radius: f32,
+    },
+    Gaussian {
+        #[serde(default = "one_and_a_half")]
+        radius: f32,
+        /// Standard deviation of the bell curve
+        #[serde(default = "half")]
+        sigma: f32,
+    },
+    /// Cubic filter from "Reconstruction Filters in Computer Graphics" by Mitchell and Netravali,
+    /// b and c trade blurring against ringing
+    Mitchell {
+        #[serde(default = "two")]
+        radius: f32,
+        #[serde(default = "one_third")]
+        b: f32,
+        #[serde(default = "one_third")]
+        c: f32,
+    },
+    /// Sinc windowed by a wider sinc, with as many lobes as the radius
+    Lanczos {
+        #[serde(default = "two")]
+        radius: f32,
+    },
+    BlackmanHarris {
+        #[serde(default = "one_and_a_half")]
+        radius: f32,
+    },
+}
+
+fn half() -> f32 {
+    0.5
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn one_and_a_half() -> f32 {
+    1.5
+}
+
+fn two() -> f32 {
+    2.0
+}
+
+fn one_third() -> f32 {
+    1.0 / 3.0
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius }
+            | Filter::BlackmanHarris { radius } => radius,
+        }
+    }
+
+    /// The filter along a single axis, x is the offset from the center of the pixel
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let radius = self.radius();
+        if x.abs() > radius {
+            return 0.0;
+        }
+
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { .. } => radius - x.abs(),
+            Filter::Gaussian { sigma, .. } => {
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { b, c, .. } => {
+                let x = (2.0 * x / radius).abs();
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            Filter::Lanczos { .. } => sinc(x) * sinc(x / radius),
+            Filter::BlackmanHarris { .. } => {
+                let t = 2.0 * PI * (x + radius) / (2.0 * radius);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+            }
+        }
+    }
+
+    /// Tabulates the filter so offsets can be sampled proportionally to it
+    pub fn sampler(&self) -> FilterSampler {
+        FilterSampler::new(self)
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Importance samples a filter by its absolute value, from "Filter Importance Sampling"
+/// by Ernst et al. Samples that land in negative lobes get negative weights, so the weighted
+/// average of the samples in a pixel converges to the filtered image.
+#[derive(Clone, Debug)]
+pub struct FilterSampler {
+    radius: f32,
+    /// Cumulative distribution of the absolute value of the filter over its bins
+    cdf: Vec<f32>,
+    /// Whether the filter is positive or negative in each bin
+    signs: Vec<f32>,
+    /// Integral of the absolute value over the integral of the filter
+    scale: f32,
+}
+
+impl FilterSampler {
+    /// Number of bins the filter is tabulated into
+    const BINS: usize = 512;
+
+    pub fn new(filter: &Filter) -> Self {
+        let radius = filter.radius().max(1e-3);
+        let width = 2.0 * radius / Self::BINS as f32;
+        let values: Vec<_> = (0..Self::BINS)
+            .map(|i| filter.evaluate(-radius + (i as f32 + 0.5) * width))
+            .collect();
+
+        let mut cdf = Vec::with_capacity(Self::BINS + 1);
+        cdf.push(0.0);
+        for value in &values {
+            cdf.push(cdf.last().unwrap() + value.abs());
+        }
+        let absolute: f32 = *cdf.last().unwrap();
+        let signed: f32 = values.iter().sum();
+
+        // A filter that is zero everywhere falls back to a box
+        let (cdf, signs, scale) = if absolute > 0.0 && signed > 0.0 {
+            let cdf = cdf.iter().map(|c| c / absolute).collect();
+            let signs = values.iter().map(|v| v.signum()).collect();
+            (cdf, signs, absolute / signed)
+        } else {
+            let cdf = (0..=Self::BINS)
+                .map(|i| i as f32 / Self::BINS as f32)
+                .collect();
+            (cdf, vec![1.0; Self::BINS], 1.0)
+        };
+
+        Self {
+            radius,
+            cdf,
+            signs,
+            scale,
+        }
+    }
+
+    // Samples an offset along one axis, and the sign of the filter there
+    fn sample_1d(&self, u: f32) -> (f32, f32) {
+        let bin = (self.cdf.partition_point(|&c| c <= u) - 1).min(Self::BINS - 1);
+        let (start, end) = (self.cdf[bin], self.cdf[bin + 1]);
+        let t = if end > start {
+            (u - start) / (end - start)
+        } else {
+            0.5
+        };
+        let offset = -self.radius + (bin as f32 + t) * 2.0 * self.radius / Self::BINS as f32;
+
+        (offset, self.signs[bin])
+    }
+
+    /// Samples an offset from the center of the pixel, and the weight of the sample
+    pub fn sample(&self, u: (f32, f32)) -> ((f32, f32), f32) {
+        let (x, x_sign) = self.sample_1d(u.0);
+        let (y, y_sign) = self.sample_1d(u.1);
+
+        ((x, y), x_sign * y_sign * self.scale * self.scale)
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{bail, ensure};
+use glam::{vec3, Vec3};
+use std::io::Read;
+
+/// A dense grid of density values covering the unit cube, sampled with trilinear interpolation.
+#[derive(Clone)]
+pub struct DensityGrid {
+    resolution: [usize; 3],
+    voxels: Vec<f32>,
+}
+
+impl std::fmt::Debug for DensityGrid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DensityGrid")
+            .field("resolution", &self.resolution)
+            .finish()
+    }
+}
+
+impl DensityGrid {
+    pub fn new(resolution: [usize; 3], voxels: Vec<f32>) -> Self {
+        assert_eq!(voxels.len(), resolution[0] * resolution[1] * resolution[2]);
+
+        Self { resolution, voxels }
+    }
+
+    /// Fills the grid by evaluating f at the center of every voxel, in unit cube coordinates
+    pub fn from_fn(resolution: [usize; 3], f: impl Fn(Vec3) -> f32) -> Self {
+        let [nx, ny, nz] = resolution;
+        let mut voxels = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let p = vec3(
+                        (x as f32 + 0.5) / nx as f32,
+                        (y as f32 + 0.5) / ny as f32,
+                        (z as f32 + 0.5) / nz as f32,
+                    );
+                    voxels.push(f(p));
+                }
+            }
+        }
+
+        Self::new(resolution, voxels)
+    }
+
+    /// A cloud like blob of fractal noise that fades out towards the edges of the grid
+    pub fn noise(resolution: [usize; 3], frequency: f32, octaves: u32, seed: u32) -> Self {
+        Self::from_fn(resolution, |p| {
+            let falloff = (1.0 - 2.0 * (p - Vec3::splat(0.5)).length()).max(0.0);
+            (fractal_noise(p * frequency, octaves, seed) * falloff * 2.0).max(0.0)
+        })
+    }
+
+    /// Loads a raw dense grid: the resolution as three little endian u32s,
+    /// followed by the densities as little endian f32s with x varying fastest.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        ensure!(
+            bytes.len() >= 12,
+            "Density grid {} is missing its header",
+            path
+        );
+        let (header, data) = bytes.split_at(12);
+        let mut resolution = [0; 3];
+        for (r, word) in resolution.iter_mut().zip(header.chunks_exact(4)) {
+            *r = u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as usize;
+        }
+        ensure!(
+            resolution.iter().all(|&r| r > 0),
+            "Density grid {} has an empty resolution {:?}",
+            path,
+            resolution
+        );
+
+        let count = resolution[0]
+            .checked_mul(resolution[1])
+            .and_then(|count| count.checked_mul(resolution[2]));
+        ensure!(
+            count.and_then(|count| count.checked_mul(4)) == Some(data.len()),
+            "Density grid {} has {} bytes of densities, but its resolution is {:?}",
+            path,
+            data.len(),
+            resolution
+        );
+        let voxels: Vec<f32> = data
+            .chunks_exact(4)
+            .map(|word| f32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        if let Some(i) = voxels.iter().position(|d| !d.is_finite() || *d < 0.0) {
+            bail!(
+                "Density grid {} has a density of {} at voxel {}, densities must be finite and \
+                 non-negative",
+                path,
+                voxels[i],
+                i
+            );
+        }
+
+        Ok(Self::new(resolution, voxels))
+    }
+
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    pub fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        let [nx, ny, _] = self.resolution;
+        self.voxels[(z * ny + y) * nx + x]
+    }
+
+    /// Trilinearly interpolated density at p in unit cube coordinates
+    pub fn density(&self, p: Vec3) -> f32 {
+        let mut base = [0; 3];
+        let mut frac = [0.0; 3];
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let x = (p[axis] * n as f32 - 0.5).max(0.0).min(n as f32 - 1.0);
+            base[axis] = (x as usize).min(n.saturating_sub(2));
+            frac[axis] = x - base[axis] as f32;
+        }
+
+        let mut density = 0.0;
+        for corner in 0..8 {
+            let mut weight = 1.0;
+            let mut index = [0; 3];
+            for axis in 0..3 {
+                let offset = (corner >> axis) & 1;
+                index[axis] = (base[axis] + offset).min(self.resolution[axis] - 1);
+                weight *= if offset == 1 {
+                    frac[axis]
+                } else {
+                    1.0 - frac[axis]
+                };
+            }
+            density += weight * self.voxel(index[0], index[1], index[2]);
+        }
+
+        density
+    }
+
+    /// Builds a coarser grid where every cell holds the max density that can be
+    /// interpolated anywhere inside of it
+    pub fn majorants(&self, resolution: [usize; 3]) -> DensityGrid {
+        let [mx, my, mz] = resolution;
+        let mut voxels = Vec::with_capacity(mx * my * mz);
+
+        // The voxels that can influence interpolation inside cell i along an axis
+        let range = |axis: usize, i: usize, m: usize| {
+            let n = self.resolution[axis];
+            let lo = (i as f32 / m as f32 * n as f32 - 0.5).floor().max(0.0) as usize;
+            let hi = ((i + 1) as f32 / m as f32 * n as f32 + 0.5).ceil() as usize;
+            lo.min(n - 1)..=hi.min(n - 1)
+        };
+
+        for z in 0..mz {
+            for y in 0..my {
+                for x in 0..mx {
+                    let mut max = 0.0f32;
+                    for vz in range(2, z, mz) {
+                        for vy in range(1, y, my) {
+                            for vx in range(0, x, mx) {
+                                max = max.max(self.voxel(vx, vy, vz));
+                            }
+                        }
+                    }
+                    voxels.push(max);
+                }
+            }
+        }
+
+        DensityGrid::new(resolution, voxels)
+    }
+}
+
+// Hashes a lattice point into [0, 1)
+fn lattice(x: i32, y: i32, z: i32, seed: u32) -> f32 {
+    let mut h = seed
+        ^ (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+
+    h as f32 / u32::MAX as f32
+}
+
+/// Smoothly interpolated value noise in [0, 1)
+pub fn value_noise(p: Vec3, seed: u32) -> f32 {
+    let base = p.floor();
+    let f = p - base;
+    let f = f * f * (Vec3::splat(3.0) - 2.0 * f);
+    let (x, y, z) = (base.x() as i32, base.y() as i32, base.z() as i32);
+
+    let mut value = 0.0;
+    for corner in 0..8 {
+        let (dx, dy, dz) = (corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+        let weight = if dx == 1 { f.x() } else { 1.0 - f.x() }
+            * if dy == 1 { f.y() } else { 1.0 - f.y() }
+            * if dz == 1 { f.z() } else { 1.0 - f.z() };
+        value += weight * lattice(x + dx, y + dy, z + dz, seed);
+    }
+
+    value
+}
+
+/// Sums octaves of value noise, each at twice the frequency and half the amplitude
+pub fn fractal_noise(p: Vec3, octaves: u32, seed: u32) -> f32 {
+    let mut value = 0.0;
+    let mut amplitude = 0.5;
+    let mut p = p;
+    for octave in 0..octaves {
+        value += amplitude * value_noise(p, seed.wrapping_add(octave));
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+
+    value
+}
@@ -1,86 +1,313 @@
+mod aov;
 mod bvh;
 mod camera;
+mod denoise;
+mod film;
+mod filter;
+mod grid;
 mod material;
+mod medium;
 mod primitives;
 mod ray;
+mod sampler;
 mod scene;
-// mod textures;
+mod settings;
+mod textures;
+mod tile;
 
-use crate::{bvh::*, material::*, primitives::*, ray::*, scene::*};
+use crate::{
+    bvh::*, film::luminance, material::*, medium::*, primitives::*, ray::*, sampler::*, scene::*,
+    settings::*,
+};
 use glam::{vec3, Vec3};
 use rand::prelude::*;
-use serde::Deserialize;
 use std::io::Read;
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
 /// Default random number generator to be used
 type DefaultRng = rand_xoshiro::Xoshiro256PlusPlus;
 
-/// Specifies settings used in the pathtracing
-#[derive(Deserialize, Debug, Clone, Copy)]
-pub struct SettingsConfig {
-    /// Resolution of the output image (width, height)
-    resolution: [u32; 2],
-    /// Number of samples per pixel
-    samples: u32,
-    /// Max bounces of a single primary ray
-    max_bounces: u32,
-    /// Gamma
-    gamma: f32,
+/// What is tracked along a path besides the light it carries
+pub struct PathState {
+    /// Number of times the path has scattered
+    pub bounces: u32,
+    /// How much of the pixel the first thing the camera sees covers
+    pub alpha: f32,
+    /// Leave the sky out where the camera sees it directly
+    pub transparent_background: bool,
+    /// Density of the direction the path last took when that was sampled from the phase function
+    /// of a medium, where the sky is also sampled directly
+    pub phase_pdf: Option<f32>,
+    /// The first surface the camera ray reaches, passing through volume boundaries
+    pub first_hit: Option<Hit>,
 }
 
-impl Default for SettingsConfig {
-    fn default() -> Self {
+impl PathState {
+    pub fn new(transparent_background: bool) -> Self {
         Self {
-            resolution: [1280, 720],
-            samples: 12,
-            max_bounces: 8,
-            gamma: 2.2,
+            bounces: 0,
+            alpha: 1.0,
+            transparent_background,
+            phase_pdf: None,
+            first_hit: None,
         }
     }
 }
 
-impl SettingsConfig {
-    pub fn width(&self) -> u32 {
-        self.resolution[0]
+/// Light carried back along a path, split by how often the path had scattered when it was
+/// picked up
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Radiance {
+    /// Light that reached the camera after at most one bounce
+    pub direct: Vec3,
+    /// Light that bounced more than once
+    pub indirect: Vec3,
+}
+
+impl Radiance {
+    /// Light picked up after the path scattered bounces times
+    fn after(bounces: u32, light: Vec3) -> Self {
+        if bounces <= 1 {
+            Self {
+                direct: light,
+                indirect: Vec3::zero(),
+            }
+        } else {
+            Self {
+                direct: Vec3::zero(),
+                indirect: light,
+            }
+        }
+    }
+
+    pub fn total(self) -> Vec3 {
+        self.direct + self.indirect
+    }
+}
+
+impl std::ops::Add for Radiance {
+    type Output = Radiance;
+
+    fn add(self, other: Radiance) -> Radiance {
+        Radiance {
+            direct: self.direct + other.direct,
+            indirect: self.indirect + other.indirect,
+        }
     }
+}
 
-    pub fn height(&self) -> u32 {
-        self.resolution[1]
+impl std::ops::Mul<Radiance> for Vec3 {
+    type Output = Radiance;
+
+    fn mul(self, radiance: Radiance) -> Radiance {
+        Radiance {
+            direct: self * radiance.direct,
+            indirect: self * radiance.indirect,
+        }
     }
 }
 
-/// Computes the color of a pixel/sample based on a ray
+/// The background/skybox seen in a direction
+fn sky(direction: Vec3) -> Vec3 {
+    let dir = direction.normalize();
+    let t = 0.5 * (dir.y() + 1.0);
+    (1.0 - t) * vec3(1.0, 1.0, 1.0) + t * vec3(0.5, 0.7, 1.0)
+}
+
+/// Density of sampling directions towards the sky, uniformly over the sphere
+const SKY_PDF: f32 = 1.0 / (4.0 * std::f32::consts::PI);
+
+/// Weight of a sample taken with density pdf, when other has the density of the other strategy
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    pdf * pdf / (pdf * pdf + other * other)
+}
+
+/// Computes the color of a pixel/sample based on a ray traveling through medium
 /// Returns color and raycount
-fn color(ray: Ray, bounces: &mut u32, bvh: &BVH, rng: &mut DefaultRng, max_bounces: u32) -> Vec3 {
+fn color(
+    ray: Ray,
+    medium: Option<&dyn Medium>,
+    path: &mut PathState,
+    bvh: &Bvh,
+    fog: Option<&dyn Medium>,
+    sampler: &mut dyn Sampler,
+    max_bounces: u32,
+) -> Radiance {
     // Max bounces
-    if *bounces >= max_bounces {
-        Vec3::zero()
+    if path.bounces >= max_bounces {
+        return Radiance::default();
     }
-    // If the ray trace hits something
-    else if let Some(hit) = bvh.intersection(ray, 0.0001, 10_000_000.0) {
-        // The material of the object we hit decides how the ray scatters
-        hit.material
-            .clone()
-            .and_then(|material| material.scatter(ray, hit, rng))
-            .map(|scatter| {
-                *bounces += 1;
-                scatter.attenuation * color(scatter.scattered, bounces, bvh, rng, max_bounces)
-            })
-            .unwrap_or_else(Vec3::zero)
+
+    let hit = bvh.intersection(ray, 0.0001, 10_000_000.0);
+    if path.bounces == 0 && path.first_hit.is_none() {
+        path.first_hit = hit.clone().filter(|hit| hit.medium.is_none());
     }
-    // Else draw the background/skybox
-    else {
-        let dir = ray.direction.normalize();
-        let t = 0.5 * (dir.y() + 1.0);
-        (1.0 - t) * vec3(1.0, 1.0, 1.0) + t * vec3(0.5, 0.7, 1.0)
+
+    // The ray might scatter inside the medium before it reaches what it hit
+    let mut weight = Vec3::one();
+    if let Some(medium) = medium {
+        let t_max = hit.as_ref().map_or(f32::INFINITY, |hit| hit.t);
+        match medium.sample(ray, t_max, sampler) {
+            MediumSample::Scatter { t, weight } => {
+                path.bounces += 1;
+                let point = ray.point_at_parameter(t);
+                let phase = medium.phase();
+
+                // Sky light reaching the point directly, unless something other than a volume
+                // is in the way
+                let direction = sample_unit_sphere(sampler);
+                let shadow = Ray::new(point, direction).at_time(ray.time);
+                let pdf = phase.pdf(ray.direction, direction);
+                let direct = Radiance::after(
+                    path.bounces,
+                    sky(direction)
+                        * transmittance(shadow, f32::INFINITY, Some(medium), bvh, fog, sampler)
+                        * pdf
+                        * power_heuristic(SKY_PDF, pdf)
+                        / SKY_PDF,
+                );
+
+                let direction = phase.sample(ray.direction, sampler);
+                path.phase_pdf = Some(phase.pdf(ray.direction, direction));
+                let scattered = Ray::new(point, direction).at_time(ray.time);
+                let indirect = color(
+                    scattered,
+                    Some(medium),
+                    path,
+                    bvh,
+                    fog,
+                    sampler,
+                    max_bounces,
+                );
+                return weight * (direct + indirect);
+            }
+            MediumSample::Pass { weight: w } => weight = w,
+        }
     }
+
+    match hit {
+        // The boundary of a volume, so the ray passes straight through into the next medium
+        Some(hit) if hit.medium.is_some() => {
+            let next = if ray.direction.dot(hit.normal) < 0.0 {
+                hit.medium.as_deref()
+            } else {
+                fog
+            };
+            let ray = pass_through(ray, &hit);
+            weight * color(ray, next, path, bvh, fog, sampler, max_bounces)
+        }
+        // Holdouts and shadow catchers seen by the camera are left for compositing
+        Some(hit) if path.bounces == 0 && coverage(&hit) == Coverage::Holdout => {
+            path.alpha = 0.0;
+            Radiance::default()
+        }
+        Some(hit) if path.bounces == 0 && coverage(&hit) == Coverage::ShadowCatcher => {
+            let material = hit.material.clone().unwrap();
+
+            // Light reaching the catcher relative to the sky it would see with nothing in the way
+            let lit = match material.scatter(ray, hit, sampler) {
+                Some(scatter) => {
+                    path.bounces += 1;
+                    let scattered = scatter.scattered.at_time(ray.time);
+                    let received = color(scattered, medium, path, bvh, fog, sampler, max_bounces);
+                    let unoccluded = luminance(sky(scattered.direction));
+                    (luminance(received.total()) / unoccluded).clamp(0.0, 1.0)
+                }
+                None => 1.0,
+            };
+
+            // The camera sees the sky behind the catcher, darkened where it is in shadow
+            if path.transparent_background {
+                path.alpha = 1.0 - lit;
+                Radiance::default()
+            } else {
+                Radiance::after(0, weight * lit * sky(ray.direction))
+            }
+        }
+        // If the ray trace hits something
+        Some(hit) => {
+            // The material of the object we hit decides how the ray scatters
+            weight
+                * hit
+                    .material
+                    .clone()
+                    .and_then(|material| material.scatter(ray, hit, sampler))
+                    .map(|scatter| {
+                        path.bounces += 1;
+                        path.phase_pdf = None;
+                        scatter.attenuation
+                            * color(
+                                scatter.scattered.at_time(ray.time),
+                                medium,
+                                path,
+                                bvh,
+                                fog,
+                                sampler,
+                                max_bounces,
+                            )
+                    })
+                    .unwrap_or_default()
+        }
+        None if path.bounces == 0 && path.transparent_background => {
+            path.alpha = 0.0;
+            Radiance::default()
+        }
+        // Else draw the background/skybox, which scattering in a medium also samples directly
+        None => {
+            let mis = path
+                .phase_pdf
+                .map_or(1.0, |pdf| power_heuristic(pdf, SKY_PDF));
+            Radiance::after(path.bounces, weight * mis * sky(ray.direction))
+        }
+    }
+}
+
+fn coverage(hit: &Hit) -> Coverage {
+    hit.material
+        .as_ref()
+        .map_or(Coverage::Opaque, |material| material.coverage())
+}
+
+/// Computes the fraction of light that makes it along a shadow ray from its origin to t_max.
+/// Volume boundaries are passed through, while any other surface blocks the light.
+fn transmittance(
+    ray: Ray,
+    t_max: f32,
+    medium: Option<&dyn Medium>,
+    bvh: &Bvh,
+    fog: Option<&dyn Medium>,
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    let hit = bvh.intersection(ray, 0.0001, t_max);
+    let t = hit.as_ref().map_or(t_max, |hit| hit.t);
+    let transmittance = medium.map_or(Vec3::one(), |medium| medium.transmittance(ray, t, sampler));
+
+    match hit {
+        Some(hit) if hit.medium.is_some() => {
+            let next = if ray.direction.dot(hit.normal) < 0.0 {
+                hit.medium.as_deref()
+            } else {
+                fog
+            };
+            let ray = pass_through(ray, &hit);
+            transmittance * self::transmittance(ray, t_max - t, next, bvh, fog, sampler)
+        }
+        Some(_) => Vec3::zero(),
+        None => transmittance,
+    }
+}
+
+/// Continues a ray on the other side of the volume boundary it hit. It starts a little off the
+/// surface, or a ray grazing the boundary would keep hitting it without getting anywhere.
+fn pass_through(ray: Ray, hit: &Hit) -> Ray {
+    let side = ray.direction.dot(hit.normal).signum();
+    Ray::new(hit.point + hit.normal * side * 0.0001, ray.direction).at_time(ray.time)
 }
 
 /// Generate a semi random scene
 // TODO: Move to scene
-fn random() -> Vec<Instance> {
-    let mut rng = rand::thread_rng();
+fn random(seed: u64, shadow_catcher: bool) -> Vec<Instance> {
+    let mut rng = DefaultRng::seed_from_u64(seed);
     let mut instances = Vec::new();
 
     // let transform = Transform::default();
@@ -88,10 +315,14 @@ fn random() -> Vec<Instance> {
     //     rotation: glam::Quat::from_rotation_x(3.0),
     //     ..Default::default()
     // };
-    let transform = Default::default();
+    let transform = Transform::default();
 
     // The big sphere
-    let material = Arc::new(Lambertian::new(vec3(0.5, 0.5, 0.5)));
+    let material: Arc<dyn Material> = if shadow_catcher {
+        Arc::new(ShadowCatcher::new(vec3(0.5, 0.5, 0.5)))
+    } else {
+        Arc::new(Lambertian::new(vec3(0.5, 0.5, 0.5)))
+    };
     let primitive = Arc::new(Sphere::new(vec3(0.0, -1000.0, 0.0), 1000.0));
     instances.push(Instance::receiver(primitive, material, transform));
 
@@ -156,13 +387,146 @@ fn load_settings() -> anyhow::Result<SettingsConfig> {
     Ok(settings)
 }
 
+/// What to render, picked on the command line
+enum Mode {
+    /// A single image to output.png
+    Single,
+    /// The frames of the animation to frame_0001.png and so on, in the range given or the one in
+    /// the settings
+    Frames(Option<[u32; 2]>),
+}
+
+/// Parses `pathtracer [--frames [FIRST-LAST]]`
+fn parse_args() -> anyhow::Result<Mode> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut mode = Mode::Single;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let range = match args.next_if(|next| !next.starts_with("--")) {
+                    Some(range) => {
+                        let (first, last) = range.split_once('-').unwrap_or((&range, &range));
+                        Some([first.parse()?, last.parse()?])
+                    }
+                    None => None,
+                };
+                mode = Mode::Frames(range);
+            }
+            _ => anyhow::bail!(
+                "Unknown argument {}, usage: pathtracer [--frames [FIRST-LAST]]",
+                arg
+            ),
+        }
+    }
+
+    Ok(mode)
+}
+
+/// Renders the scene and saves the image, passes and denoised image named after the output
+fn render(
+    scene: &Scene,
+    settings: &SettingsConfig,
+    output: &str,
+    prefix: &str,
+) -> anyhow::Result<()> {
+    let mut film = scene.film()?;
+    scene.trace(&mut film);
+    if settings.region.map_or(false, |region| region.crop) {
+        film = film.crop();
+    }
+    let image = |colors: &[Vec3]| {
+        if settings.alpha {
+            Image::from_colors_with_alpha(
+                colors,
+                &film.alphas(),
+                film.width(),
+                film.height(),
+                settings.gamma,
+            )
+        } else {
+            Image::from_colors(colors, film.width(), film.height(), settings.gamma)
+        }
+    };
+
+    image(&film.colors()).save(output)?;
+    film.save_aovs(prefix)?;
+
+    if let Some(config) = settings.denoise {
+        let colors = denoise::denoise(&film, config);
+        image(&colors).save(&format!("{}denoised.png", prefix))?;
+    }
+
+    Ok(())
+}
+
+/// Renders the frames of the animation in the range given or the one in the settings
+fn render_frames(
+    scene: &mut Scene,
+    settings: &SettingsConfig,
+    range: Option<[u32; 2]>,
+) -> anyhow::Result<()> {
+    let animation = settings.animation.clone().unwrap_or_default();
+    let [first, last] = range.or(animation.frames).ok_or_else(|| {
+        anyhow::anyhow!("No frames to render, give a range after --frames or in [animation]")
+    })?;
+
+    for frame in first..=last {
+        let name = format!("frame_{:04}", frame);
+        println!("Rendering {}", name);
+
+        scene.set_frame(&name, settings.camera.at_frame(&animation, frame))?;
+        render(
+            scene,
+            settings,
+            &format!("{}.png", name),
+            &format!("{}_", name),
+        )?;
+
+        if scene.stopped() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
 fn main() {
+    let mode = parse_args().expect("Failed to parse arguments");
+
     // Load in settings
     let settings: SettingsConfig = load_settings().unwrap_or_default();
+    println!("Seed: {}", settings.seed());
+
+    let mut instances = random(settings.seed(), settings.shadow_catcher);
+    for mesh in &settings.meshes {
+        instances.push(mesh.instance().expect("Failed to load mesh"));
+    }
+    for object in &settings.objects {
+        instances.push(object.instance().expect("Failed to build object"));
+    }
 
-    let scene = Scene::new(settings, random());
-    let image = scene.trace();
-    image
-        .save("output.png")
-        .expect("Failed to save output image");
+    let mut scene = Scene::new(settings.clone(), instances).expect("Failed to build scene");
+
+    // Stop a progressive render or a sequence of frames after the current pass and save what it
+    // has so far, a second Ctrl-C exits right away
+    if settings.progressive.is_some() || matches!(mode, Mode::Frames(_)) {
+        let stop = scene.stop_flag();
+        ctrlc::set_handler(move || {
+            if stop.swap(true, Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+            println!("Stopping after the current pass");
+        })
+        .expect("Failed to set Ctrl-C handler");
+    }
+
+    let rendered = match mode {
+        Mode::Single => render(&scene, &settings, "output.png", ""),
+        Mode::Frames(range) => render_frames(&mut scene, &settings, range),
+    };
+    if let Err(error) = rendered {
+        eprintln!("Failed to render: {:#}", error);
+        std::process::exit(1);
+    }
 }
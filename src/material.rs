@@ -1,11 +1,27 @@
-use crate::{DefaultRng, Hit, Ray};
+use crate::{sampler::Sampler, textures::Texture, Hit, Ray, ShadingFrame};
 use glam::{vec3, Vec3};
-use rand::prelude::*;
-use rand_distr::{Distribution, UnitSphere};
+use std::{f32::consts::PI, sync::Arc};
 
-// Samples a random point in a unit sphere from the thread rng
-pub fn sample_unit_sphere(rng: &mut DefaultRng) -> Vec3 {
-    Vec3::from(UnitSphere.sample(rng))
+// Samples a random point on the unit sphere from the next two dimensions of the sampler
+pub fn sample_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    let (u, v) = sampler.next_2d();
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+
+    vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Builds two vectors that together with the unit vector n form an orthonormal basis
+pub fn orthonormal_basis(n: Vec3) -> (Vec3, Vec3) {
+    let sign = 1.0f32.copysign(n.z());
+    let a = -1.0 / (sign + n.z());
+    let b = n.x() * n.y() * a;
+
+    (
+        vec3(1.0 + sign * n.x() * n.x() * a, sign * b, -sign * n.x()),
+        vec3(b, sign + n.y() * n.y() * a, -n.y()),
+    )
 }
 
 // Reflect vector v around normal n
@@ -35,13 +51,49 @@ pub fn schlick(cosine: f32, reflection_index: f32) -> f32 {
     r_0 + (1.0 - r_0) * f32::powf(1.0 - cosine, 5.0)
 }
 
+// Bent shading normals can send rays to the wrong side of the actual surface, which would leak
+// light through it. Such rays are mirrored back across the surface.
+fn keep_side(direction: Vec3, normal: Vec3, above: bool) -> Vec3 {
+    let d = direction.dot(normal);
+    if d == 0.0 || (d > 0.0) == above {
+        direction
+    } else {
+        direction - 2.0 * d * normal
+    }
+}
+
 pub struct ScatterResult {
     pub scattered: Ray,
     pub attenuation: Vec3,
 }
 
+/// How a surface the camera sees directly shows up in the image, for compositing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coverage {
+    /// Covers the pixel with its own color
+    Opaque,
+    /// Cuts a transparent hole into the image
+    Holdout,
+    /// Only covers the pixel where shadows fall onto it, showing the background otherwise
+    ShadowCatcher,
+}
+
 pub trait Material: std::fmt::Debug + Send + Sync {
-    fn scatter(&self, ray: Ray, hit: Hit, rng: &mut DefaultRng) -> Option<ScatterResult>;
+    fn scatter(&self, ray: Ray, hit: Hit, sampler: &mut dyn Sampler) -> Option<ScatterResult>;
+
+    /// The overall color of the surface, written out as the albedo pass
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        Vec3::one()
+    }
+
+    fn coverage(&self) -> Coverage {
+        Coverage::Opaque
+    }
+
+    /// The frame the surface is shaded in, which normal and bump maps perturb
+    fn shading(&self, hit: &Hit) -> ShadingFrame {
+        hit.shading
+    }
 }
 
 #[derive(Debug)]
@@ -56,14 +108,18 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _ray: Ray, hit: Hit, rng: &mut DefaultRng) -> Option<ScatterResult> {
-        let target = hit.point + hit.normal + sample_unit_sphere(rng);
+    fn scatter(&self, _ray: Ray, hit: Hit, sampler: &mut dyn Sampler) -> Option<ScatterResult> {
+        let target = hit.point + hit.shading.normal + sample_unit_sphere(sampler);
 
         Some(ScatterResult {
-            scattered: Ray::new(hit.point, target - hit.point),
+            scattered: Ray::new(hit.point, keep_side(target - hit.point, hit.normal, true)),
             attenuation: self.albedo,
         })
     }
+
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        self.albedo
+    }
 }
 
 #[derive(Debug)]
@@ -79,10 +135,14 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray: Ray, hit: Hit, rng: &mut DefaultRng) -> Option<ScatterResult> {
-        let reflected = reflect(ray.direction.normalize(), hit.normal);
-        let scattered = Ray::new(hit.point, reflected + self.fuzz * sample_unit_sphere(rng));
+    fn scatter(&self, ray: Ray, hit: Hit, sampler: &mut dyn Sampler) -> Option<ScatterResult> {
+        let reflected = reflect(ray.direction.normalize(), hit.shading.normal);
+        let scattered = Ray::new(
+            hit.point,
+            reflected + self.fuzz * sample_unit_sphere(sampler),
+        );
 
+        // Reflections through the actual surface are absorbed
         if scattered.direction.dot(hit.normal) > 0.0 {
             Some(ScatterResult {
                 scattered,
@@ -92,6 +152,182 @@ impl Material for Metal {
             None
         }
     }
+
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        self.albedo
+    }
+}
+
+/// Stands in for an object of the plate a render is composited over. The camera sees a hole
+/// where it is, while it blocks light like a black surface.
+#[derive(Debug)]
+pub struct Holdout;
+
+impl Material for Holdout {
+    fn scatter(&self, _ray: Ray, _hit: Hit, _sampler: &mut dyn Sampler) -> Option<ScatterResult> {
+        None
+    }
+
+    fn albedo(&self, _hit: &Hit) -> Vec3 {
+        Vec3::zero()
+    }
+
+    fn coverage(&self) -> Coverage {
+        Coverage::Holdout
+    }
+}
+
+/// Stands in for the ground of a plate, so the shadows cast onto it can be composited over the
+/// plate. It reflects light like a diffuse surface to the rest of the scene.
+#[derive(Debug)]
+pub struct ShadowCatcher {
+    surface: Lambertian,
+}
+
+impl ShadowCatcher {
+    pub fn new(albedo: Vec3) -> Self {
+        Self {
+            surface: Lambertian::new(albedo),
+        }
+    }
+}
+
+impl Material for ShadowCatcher {
+    fn scatter(&self, ray: Ray, hit: Hit, sampler: &mut dyn Sampler) -> Option<ScatterResult> {
+        self.surface.scatter(ray, hit, sampler)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.surface.albedo(hit)
+    }
+
+    fn coverage(&self) -> Coverage {
+        Coverage::ShadowCatcher
+    }
+}
+
+/// Bends the shading normal of a material with a tangent space normal map. Red, green and blue
+/// map from [0, 1] to [-1, 1] along the tangent, bitangent and normal, with green pointing
+/// towards growing v like in glTF.
+#[derive(Debug)]
+pub struct NormalMap {
+    material: Arc<dyn Material>,
+    map: Arc<dyn Texture>,
+    /// Scales how far the normal is bent
+    strength: f32,
+}
+
+impl NormalMap {
+    pub fn new(material: Arc<dyn Material>, map: Arc<dyn Texture>) -> Self {
+        Self {
+            material,
+            map,
+            strength: 1.0,
+        }
+    }
+
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.strength = strength;
+        self
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(&self, ray: Ray, mut hit: Hit, sampler: &mut dyn Sampler) -> Option<ScatterResult> {
+        hit.shading = self.shading(&hit);
+        self.material.scatter(ray, hit, sampler)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.material.albedo(hit)
+    }
+
+    fn coverage(&self) -> Coverage {
+        self.material.coverage()
+    }
+
+    fn shading(&self, hit: &Hit) -> ShadingFrame {
+        let frame = self.material.shading(hit);
+        let (u, v) = hit.uv;
+        let value = 2.0 * self.map.value(u, v) - Vec3::one();
+        let local = vec3(
+            self.strength * value.x(),
+            self.strength * value.y(),
+            value.z(),
+        );
+
+        bend(hit, frame, frame.to_world(local))
+    }
+}
+
+/// Bends the shading normal of a material along the slope of a height texture, whose height is
+/// the average of its channels
+#[derive(Debug)]
+pub struct BumpMap {
+    material: Arc<dyn Material>,
+    height: Arc<dyn Texture>,
+    /// Height of a texture value of one, in scene units per unit of texture coordinates
+    scale: f32,
+}
+
+impl BumpMap {
+    /// Distance in texture coordinates the slope is measured over
+    const DELTA: f32 = 1e-3;
+
+    pub fn new(material: Arc<dyn Material>, height: Arc<dyn Texture>, scale: f32) -> Self {
+        Self {
+            material,
+            height,
+            scale,
+        }
+    }
+}
+
+impl Material for BumpMap {
+    fn scatter(&self, ray: Ray, mut hit: Hit, sampler: &mut dyn Sampler) -> Option<ScatterResult> {
+        hit.shading = self.shading(&hit);
+        self.material.scatter(ray, hit, sampler)
+    }
+
+    fn albedo(&self, hit: &Hit) -> Vec3 {
+        self.material.albedo(hit)
+    }
+
+    fn coverage(&self) -> Coverage {
+        self.material.coverage()
+    }
+
+    fn shading(&self, hit: &Hit) -> ShadingFrame {
+        let frame = self.material.shading(hit);
+        let (u, v) = hit.uv;
+        let delta = Self::DELTA;
+        let height = |u, v| self.height.scalar(u, v);
+        let du = (height(u + delta, v) - height(u - delta, v)) / (2.0 * delta);
+        let dv = (height(u, v + delta) - height(u, v - delta)) / (2.0 * delta);
+
+        let normal = frame.normal - self.scale * (du * frame.tangent + dv * frame.bitangent);
+        bend(hit, frame, normal)
+    }
+}
+
+// Turns the frame towards a bent normal. Normals bent too close to the actual surface are pulled
+// back up, or light would leak past its edge.
+fn bend(hit: &Hit, frame: ShadingFrame, normal: Vec3) -> ShadingFrame {
+    const MIN_COSINE: f32 = 0.05;
+
+    if normal.length_squared() < 1e-12 {
+        return frame;
+    }
+    let normal = normal.normalize();
+    let surface = hit.normal * frame.normal.dot(hit.normal).signum();
+    let cosine = normal.dot(surface);
+    let normal = if cosine < MIN_COSINE {
+        (normal + (MIN_COSINE - cosine) * surface).normalize()
+    } else {
+        normal
+    };
+
+    ShadingFrame::new(normal, frame.tangent)
 }
 
 #[derive(Debug)]
@@ -106,23 +342,27 @@ impl Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray: Ray, hit: Hit, rng: &mut DefaultRng) -> Option<ScatterResult> {
+    fn scatter(&self, ray: Ray, hit: Hit, sampler: &mut dyn Sampler) -> Option<ScatterResult> {
         let outward_normal;
         let ni_over_nt;
         let cosine;
 
-        if ray.direction.dot(hit.normal) > 0.0 {
-            outward_normal = -hit.normal;
+        // The actual surface decides whether the ray is leaving, the shading normal how it bends
+        let entering = ray.direction.dot(hit.normal) <= 0.0;
+        let normal = hit.shading.normal;
+        if !entering {
+            outward_normal = -normal;
             ni_over_nt = self.reflection_index;
-            cosine = self.reflection_index * ray.direction.dot(hit.normal) / ray.direction.length();
+            cosine = self.reflection_index * ray.direction.dot(normal) / ray.direction.length();
         } else {
-            outward_normal = hit.normal;
+            outward_normal = normal;
             ni_over_nt = 1.0 / self.reflection_index;
-            cosine = -ray.direction.dot(hit.normal) / ray.direction.length();
+            cosine = -ray.direction.dot(normal) / ray.direction.length();
         }
 
-        let reflected = reflect(ray.direction, hit.normal);
-        let refracted = refract(ray.direction, outward_normal, ni_over_nt);
+        let reflected = keep_side(reflect(ray.direction, normal), hit.normal, entering);
+        let refracted = refract(ray.direction, outward_normal, ni_over_nt)
+            .map(|refracted| keep_side(refracted, hit.normal, !entering));
 
         // Probability decides if we reflect or refract
         let reflect_prob = if refracted.is_some() {
@@ -132,7 +372,7 @@ impl Material for Dielectric {
         };
 
         // Reflect or refract based on probability
-        let scattered = if rng.gen_bool(reflect_prob.into()) {
+        let scattered = if sampler.next_1d() < reflect_prob {
             Ray::new(hit.point, reflected)
         } else {
             Ray::new(hit.point, refracted.unwrap())
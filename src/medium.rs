@@ -0,0 +1,361 @@
+use crate::{
+    grid::DensityGrid,
+    material::orthonormal_basis,
+    primitives::{Aabb, AnimatedTransform, Transform},
+    sampler::Sampler,
+    Ray,
+};
+use glam::{vec3, Vec3};
+use std::{f32::consts::PI, sync::Arc};
+
+/// Henyey-Greenstein phase function, g in (-1, 1) goes from back scattering to forward scattering
+#[derive(Clone, Copy, Debug)]
+pub struct HenyeyGreenstein {
+    g: f32,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f32) -> Self {
+        Self { g }
+    }
+
+    /// Samples a new direction relative to the direction the ray is traveling in.
+    /// The phase function is sampled exactly, so the sample weight is always one.
+    pub fn sample(&self, direction: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let g = self.g;
+        let (u1, u2) = sampler.next_2d();
+
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+
+        let w = direction.normalize();
+        let (u, v) = orthonormal_basis(w);
+        sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * w
+    }
+
+    /// Density of scattering into the scattered direction over the sphere of directions,
+    /// which is also the value of the phase function
+    pub fn pdf(&self, direction: Vec3, scattered: Vec3) -> f32 {
+        let g = self.g;
+        let cos_theta = direction.normalize().dot(scattered.normalize());
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(0.0).sqrt())
+    }
+}
+
+/// The outcome of sampling a free flight distance through a medium
+#[derive(Clone, Copy, Debug)]
+pub enum MediumSample {
+    /// The ray scattered at t, and must be weighted by weight
+    Scatter { t: f32, weight: Vec3 },
+    /// The ray made it through to t_max, and must be weighted by weight
+    Pass { weight: Vec3 },
+}
+
+/// A participating medium that rays scatter and get absorbed in
+pub trait Medium: std::fmt::Debug + Send + Sync {
+    /// Samples a free flight distance along the ray before t_max
+    fn sample(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> MediumSample;
+
+    /// The fraction of light that makes it through the medium along the ray up to t_max
+    fn transmittance(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> Vec3;
+
+    /// The phase function used when the ray scatters inside the medium
+    fn phase(&self) -> HenyeyGreenstein;
+}
+
+/// A medium with the same density everywhere
+#[derive(Clone, Copy, Debug)]
+pub struct ConstantMedium {
+    /// Absorption coefficient
+    sigma_a: Vec3,
+    /// Scattering coefficient
+    sigma_s: Vec3,
+    phase: HenyeyGreenstein,
+}
+
+impl ConstantMedium {
+    pub fn new(sigma_a: Vec3, sigma_s: Vec3, g: f32) -> Self {
+        Self {
+            sigma_a,
+            sigma_s,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+}
+
+impl Medium for ConstantMedium {
+    // Distances are sampled proportionally to the extinction of a randomly picked channel,
+    // and weighted by the average pdf of all the channels.
+    fn sample(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> MediumSample {
+        let sigma_t = self.sigma_t();
+        let speed = ray.direction.length();
+
+        let channel = sigma_t[((sampler.next_1d() * 3.0) as usize).min(2)];
+        let distance = if channel > 0.0 {
+            -(1.0 - sampler.next_1d()).ln() / channel
+        } else {
+            f32::INFINITY
+        };
+        let t = distance / speed;
+
+        if t < t_max {
+            let transmittance = exp(-sigma_t * distance);
+            let pdf = mean(sigma_t * transmittance);
+
+            MediumSample::Scatter {
+                t,
+                weight: transmittance * self.sigma_s / pdf,
+            }
+        } else {
+            let transmittance = exp(-sigma_t * t_max * speed);
+            let pdf = mean(transmittance);
+            let weight = if pdf > 0.0 {
+                transmittance / pdf
+            } else {
+                Vec3::zero()
+            };
+
+            MediumSample::Pass { weight }
+        }
+    }
+
+    fn transmittance(&self, ray: Ray, t_max: f32, _sampler: &mut dyn Sampler) -> Vec3 {
+        exp(-self.sigma_t() * t_max * ray.direction.length())
+    }
+
+    fn phase(&self) -> HenyeyGreenstein {
+        self.phase
+    }
+}
+
+/// A scene wide medium filling everything outside of volumes.
+/// Rays that escape the scene only travel through distance units of it on their way to the sky.
+#[derive(Clone, Copy, Debug)]
+pub struct Fog {
+    medium: ConstantMedium,
+    distance: f32,
+}
+
+impl Fog {
+    pub fn new(medium: ConstantMedium, distance: f32) -> Self {
+        Self { medium, distance }
+    }
+
+    // Rays that hit something keep the whole way there
+    fn clamp(&self, ray: Ray, t_max: f32) -> f32 {
+        if t_max.is_finite() {
+            t_max
+        } else {
+            self.distance / ray.direction.length()
+        }
+    }
+}
+
+impl Medium for Fog {
+    fn sample(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> MediumSample {
+        self.medium.sample(ray, self.clamp(ray, t_max), sampler)
+    }
+
+    fn transmittance(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        self.medium
+            .transmittance(ray, self.clamp(ray, t_max), sampler)
+    }
+
+    fn phase(&self) -> HenyeyGreenstein {
+        self.medium.phase()
+    }
+}
+
+/// A medium with its density given by a voxel grid stretched over bounds in the space of its
+/// transform, which follows the object it fills. Free flights are sampled with delta tracking and transmittance estimated with ratio tracking,
+/// both against a coarse grid of majorants so empty space is skipped quickly.
+#[derive(Clone, Debug)]
+pub struct GridMedium {
+    density: Arc<DensityGrid>,
+    majorants: DensityGrid,
+    bounds: Aabb,
+    transform: AnimatedTransform,
+    /// Extinction coefficient at a density of one
+    sigma_t: f32,
+    /// Fraction of extinction that is scattering rather than absorption
+    albedo: Vec3,
+    phase: HenyeyGreenstein,
+}
+
+impl GridMedium {
+    pub fn new(
+        density: Arc<DensityGrid>,
+        bounds: Aabb,
+        sigma_t: f32,
+        albedo: Vec3,
+        g: f32,
+    ) -> Self {
+        // Roughly one majorant cell per 8^3 voxels
+        let [nx, ny, nz] = density.resolution();
+        let majorants = density.majorants([(nx + 7) / 8, (ny + 7) / 8, (nz + 7) / 8]);
+
+        Self {
+            density,
+            majorants,
+            bounds,
+            transform: Transform::default().into(),
+            sigma_t,
+            albedo,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    /// Places the grid with the transform of the object it fills, its bounds are then in the
+    /// local space of the object
+    pub fn with_transform(mut self, transform: AnimatedTransform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// Splits the part of the local ray inside the bounds into segments through the majorant grid,
+    /// by walking the cells it passes with a 3D DDA. Returns (t_start, t_end, majorant).
+    fn segments(&self, ray: Ray, t_max: f32) -> Vec<(f32, f32, f32)> {
+        let mut segments = Vec::new();
+        let (t_start, t_end) = match self.bounds.clip(ray, 0.0, t_max) {
+            Some(range) => range,
+            None => return segments,
+        };
+
+        // Ray in majorant grid coordinates, the parameterization in t stays the same
+        let resolution = self.majorants.resolution();
+        let scale = vec3(
+            resolution[0] as f32,
+            resolution[1] as f32,
+            resolution[2] as f32,
+        ) / (self.bounds.max - self.bounds.min);
+        let origin = (ray.origin - self.bounds.min) * scale;
+        let direction = ray.direction * scale;
+
+        let start = origin + t_start * direction;
+        let mut cell = [0usize; 3];
+        let mut step = [0isize; 3];
+        let mut next = [f32::INFINITY; 3];
+        let mut delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            let c = (start[axis].max(0.0) as usize).min(resolution[axis] - 1);
+            cell[axis] = c;
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                next[axis] = t_start + ((c + 1) as f32 - start[axis]) / direction[axis];
+                delta[axis] = 1.0 / direction[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                next[axis] = t_start + (c as f32 - start[axis]) / direction[axis];
+                delta[axis] = -1.0 / direction[axis];
+            }
+        }
+
+        let mut t = t_start;
+        while t < t_end {
+            let axis = (0..3).fold(0, |a, i| if next[i] < next[a] { i } else { a });
+            let end = next[axis].min(t_end);
+            let majorant = self.majorants.voxel(cell[0], cell[1], cell[2]);
+            segments.push((t, end, majorant));
+            t = end;
+
+            let c = cell[axis] as isize + step[axis];
+            if c < 0 || c >= resolution[axis] as isize {
+                break;
+            }
+            cell[axis] = c as usize;
+            next[axis] += delta[axis];
+        }
+
+        segments
+    }
+
+    fn density(&self, p: Vec3) -> f32 {
+        self.density
+            .density((p - self.bounds.min) / (self.bounds.max - self.bounds.min))
+    }
+}
+
+impl Medium for GridMedium {
+    // Delta tracking, tentative collisions are real with probability density / majorant
+    fn sample(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> MediumSample {
+        // Extinction is per unit of world space distance, the densities are looked up locally
+        let speed = ray.direction.length();
+        let ray = self.transform.at(ray.time).ray_to_local(ray);
+
+        for (start, end, majorant) in self.segments(ray, t_max) {
+            let sigma_maj = majorant * self.sigma_t * speed;
+            if sigma_maj <= 0.0 {
+                continue;
+            }
+
+            let mut t = start;
+            loop {
+                t -= (1.0 - sampler.next_1d()).ln() / sigma_maj;
+                if t >= end {
+                    break;
+                }
+
+                if sampler.next_1d() * majorant < self.density(ray.point_at_parameter(t)) {
+                    return MediumSample::Scatter {
+                        t,
+                        weight: self.albedo,
+                    };
+                }
+            }
+        }
+
+        MediumSample::Pass {
+            weight: Vec3::one(),
+        }
+    }
+
+    // Ratio tracking, every tentative collision scales the transmittance by its null fraction
+    fn transmittance(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        let speed = ray.direction.length();
+        let ray = self.transform.at(ray.time).ray_to_local(ray);
+        let mut transmittance = 1.0;
+
+        for (start, end, majorant) in self.segments(ray, t_max) {
+            let sigma_maj = majorant * self.sigma_t * speed;
+            if sigma_maj <= 0.0 {
+                continue;
+            }
+
+            let mut t = start;
+            loop {
+                t -= (1.0 - sampler.next_1d()).ln() / sigma_maj;
+                if t >= end {
+                    break;
+                }
+
+                transmittance *= 1.0 - self.density(ray.point_at_parameter(t)) / majorant;
+            }
+        }
+
+        Vec3::splat(transmittance)
+    }
+
+    fn phase(&self) -> HenyeyGreenstein {
+        self.phase
+    }
+}
+
+fn exp(v: Vec3) -> Vec3 {
+    vec3(v.x().exp(), v.y().exp(), v.z().exp())
+}
+
+fn mean(v: Vec3) -> f32 {
+    (v.x() + v.y() + v.z()) / 3.0
+}
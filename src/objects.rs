@@ -1,4 +1,4 @@
-use crate::{primitives::AABB, Ray};
+use crate::{primitives::Aabb, Ray};
 use glam::Vec3;
 
 
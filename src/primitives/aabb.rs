@@ -1,19 +1,23 @@
-use crate::{bvh::Axis, Hit, Intersect, Ray};
+use crate::{
+    bvh::Axis,
+    primitives::{Interval, Intervals},
+    Hit, Intersect, Ray,
+};
 use glam::{vec3, Vec3};
 
 #[derive(Clone, Copy, Debug, Default)]
-pub struct AABB {
+pub struct Aabb {
     pub min: Vec3,
     pub max: Vec3,
 }
 
-impl AABB {
+impl Aabb {
     pub fn new(min: Vec3, max: Vec3) -> Self {
         Self { min, max }
     }
 
     // Create a union AABB of two AABBs that surrounds both of them
-    pub fn union(self, other: AABB) -> Self {
+    pub fn union(self, other: Aabb) -> Self {
         let min = vec3(
             self.min.x().min(other.min.x()),
             self.min.y().min(other.min.y()),
@@ -25,7 +29,7 @@ impl AABB {
             self.max.z().max(other.max.z()),
         );
 
-        AABB::new(min, max)
+        Aabb::new(min, max)
     }
 
     pub fn point_union(self, other: Vec3) -> Self {
@@ -40,7 +44,7 @@ impl AABB {
             self.max.z().max(other.z()),
         );
 
-        AABB::new(min, max)
+        Aabb::new(min, max)
     }
 
     // Returns the axis which has greatest extent
@@ -60,11 +64,29 @@ impl AABB {
         let d = self.max - self.min;
         2.0 * (d.x() * d.y() + d.x() * d.z() + d.y() * d.z())
     }
+
+    // Clips the ray parameter range [t_min, t_max] to the part inside the AABB
+    pub fn clip(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let t1 = (self.min - ray.origin) * ray.inv_direction;
+        let t2 = (self.max - ray.origin) * ray.inv_direction;
+
+        let near = t1.min(t2).max_element().max(t_min);
+        let far = t1.max(t2).min_element().min(t_max);
+
+        if near <= far {
+            Some((near, far))
+        } else {
+            None
+        }
+    }
 }
 
-impl Intersect for AABB {
-    fn intersection(&self, _ray: Ray, _t_min: f32, _t_max: f32) -> Option<Hit> {
-        unimplemented!()
+impl Intersect for Aabb {
+    fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        self.intervals(ray)
+            .into_iter()
+            .flat_map(|interval| [interval.enter, interval.exit])
+            .find(|hit| t_min < hit.t && hit.t < t_max)
     }
 
     // Taken from tavianator.com
@@ -87,7 +109,53 @@ impl Intersect for AABB {
         tmax >= f32::max(tmin, 0.0)
     }
 
-    fn bounds(&self) -> Option<AABB> {
+    fn bounds(&self) -> Option<Aabb> {
         Some(*self)
     }
+
+    fn closed(&self) -> bool {
+        true
+    }
+
+    fn intervals(&self, ray: Ray) -> Intervals {
+        let t1 = (self.min - ray.origin) * ray.inv_direction;
+        let t2 = (self.max - ray.origin) * ray.inv_direction;
+        let near = t1.min(t2);
+        let far = t1.max(t2);
+
+        // The ray enters through the last slab it enters and exits through the first it exits
+        let enter_axis = (0..3).fold(0, |a, i| if near[i] > near[a] { i } else { a });
+        let exit_axis = (0..3).fold(0, |a, i| if far[i] < far[a] { i } else { a });
+
+        let mut intervals = Intervals::new();
+        if near[enter_axis] <= far[exit_axis] {
+            let hit = |t: f32, axis: usize, sign: f32| {
+                let mut normal = Vec3::zero();
+                normal[axis] = sign * ray.direction[axis].signum();
+                let point = ray.point_at_parameter(t);
+
+                // Each face is mapped over the whole texture along the next two axes, mirrored on
+                // the faces looking down the axis so the texture reads the same from outside
+                let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+                let size = self.max - self.min;
+                let outward = normal[axis];
+                let u = (point[a] - self.min[a]) / size[a];
+                let uv = (
+                    if outward > 0.0 { u } else { 1.0 - u },
+                    (point[b] - self.min[b]) / size[b],
+                );
+                let mut tangent = Vec3::zero();
+                tangent[a] = outward;
+
+                Hit::new(t, point, normal).with_uv(uv, tangent)
+            };
+
+            intervals.push(Interval {
+                enter: hit(near[enter_axis], enter_axis, -1.0),
+                exit: hit(far[exit_axis], exit_axis, 1.0),
+            });
+        }
+
+        intervals
+    }
 }
@@ -0,0 +1,254 @@
+use crate::{primitives::Aabb, Hit, Intersect, Ray};
+use anyhow::ensure;
+use serde::Deserialize;
+use smallvec::SmallVec;
+use std::{cmp::Ordering, sync::Arc};
+
+/// A span along a ray where it is inside a solid, from where it enters to where it exits
+#[derive(Clone, Debug)]
+pub struct Interval {
+    pub enter: Hit,
+    pub exit: Hit,
+}
+
+/// Most primitives are convex and produce at most a single interval
+pub type Intervals = SmallVec<[Interval; 2]>;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    /// Everything inside the left solid that is not inside the right one
+    Difference,
+}
+
+impl CsgOp {
+    fn inside(self, left: bool, right: bool) -> bool {
+        match self {
+            CsgOp::Union => left || right,
+            CsgOp::Intersection => left && right,
+            CsgOp::Difference => left && !right,
+        }
+    }
+}
+
+/// Constructive solid geometry node combining two closed primitives
+#[derive(Clone, Debug)]
+pub struct Csg {
+    op: CsgOp,
+    left: Arc<dyn Intersect>,
+    right: Arc<dyn Intersect>,
+}
+
+impl Csg {
+    pub fn new(
+        op: CsgOp,
+        left: Arc<dyn Intersect>,
+        right: Arc<dyn Intersect>,
+    ) -> anyhow::Result<Self> {
+        // Open surfaces like meshes have no intervals, they would silently drop out
+        ensure!(
+            left.closed() && right.closed(),
+            "CSG can only combine closed shapes"
+        );
+
+        Ok(Self { op, left, right })
+    }
+}
+
+/// A point where the ray crosses the boundary of one of the children
+struct Event {
+    hit: Hit,
+    left: bool,
+    enter: bool,
+}
+
+impl Intersect for Csg {
+    fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        // The first boundary in range, which is an exit if the ray starts inside the solid
+        self.intervals(ray)
+            .into_iter()
+            .flat_map(|interval| [interval.enter, interval.exit])
+            .find(|hit| t_min < hit.t && hit.t < t_max)
+    }
+
+    fn has_intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> bool {
+        self.intersection(ray, t_min, t_max).is_some()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let left = self.left.bounds()?;
+
+        match self.op {
+            CsgOp::Union => Some(left.union(self.right.bounds()?)),
+            CsgOp::Intersection => {
+                let right = self.right.bounds()?;
+                Some(Aabb::new(left.min.max(right.min), left.max.min(right.max)))
+            }
+            CsgOp::Difference => Some(left),
+        }
+    }
+
+    fn closed(&self) -> bool {
+        true
+    }
+
+    fn intervals(&self, ray: Ray) -> Intervals {
+        let mut events = Vec::new();
+        for (left, intervals) in [
+            (true, self.left.intervals(ray)),
+            (false, self.right.intervals(ray)),
+        ] {
+            for interval in intervals {
+                events.push(Event {
+                    hit: interval.enter,
+                    left,
+                    enter: true,
+                });
+                events.push(Event {
+                    hit: interval.exit,
+                    left,
+                    enter: false,
+                });
+            }
+        }
+        events.sort_unstable_by(|a, b| a.hit.t.partial_cmp(&b.hit.t).unwrap_or(Ordering::Equal));
+
+        // Sweep along the ray and keep track of which solids we are inside
+        let mut intervals = Intervals::new();
+        let (mut in_left, mut in_right) = (false, false);
+        let mut enter = None;
+        for mut event in events {
+            let was_inside = self.op.inside(in_left, in_right);
+            if event.left {
+                in_left = event.enter;
+            } else {
+                in_right = event.enter;
+            }
+            let inside = self.op.inside(in_left, in_right);
+
+            // Surfaces of the subtracted solid face the other way
+            if let (CsgOp::Difference, false) = (self.op, event.left) {
+                event.hit.flip();
+            }
+
+            if !was_inside && inside {
+                enter = Some(event.hit);
+            } else if was_inside && !inside {
+                if let Some(enter) = enter.take() {
+                    intervals.push(Interval {
+                        enter,
+                        exit: event.hit,
+                    });
+                }
+            }
+        }
+
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::Sphere;
+    use glam::{vec3, Vec3};
+
+    fn sphere(x: f32, radius: f32) -> Arc<dyn Intersect> {
+        Arc::new(Sphere::new(vec3(x, 0.0, 0.0), radius))
+    }
+
+    fn spans(intervals: &Intervals) -> Vec<(f32, f32)> {
+        intervals
+            .iter()
+            .map(|interval| (interval.enter.t, interval.exit.t))
+            .collect()
+    }
+
+    fn assert_spans(intervals: &Intervals, expected: &[(f32, f32)]) {
+        let spans = spans(intervals);
+        assert_eq!(spans.len(), expected.len(), "{:?}", spans);
+        for (&(enter, exit), &(a, b)) in spans.iter().zip(expected) {
+            assert!((enter - a).abs() < 1e-4, "{:?}", spans);
+            assert!((exit - b).abs() < 1e-4, "{:?}", spans);
+        }
+    }
+
+    #[test]
+    fn difference_of_concentric_spheres_is_two_intervals() {
+        let csg = Csg::new(CsgOp::Difference, sphere(0.0, 1.0), sphere(0.0, 0.5)).unwrap();
+        let ray = Ray::new(vec3(-5.0, 0.0, 0.0), Vec3::unit_x());
+
+        let intervals = csg.intervals(ray);
+        assert_spans(&intervals, &[(4.0, 4.5), (5.5, 6.0)]);
+
+        // The inner surface faces into the hollow
+        assert!(intervals[0].exit.normal.x() > 0.99);
+        assert!(intervals[1].enter.normal.x() < -0.99);
+    }
+
+    #[test]
+    fn union_of_overlapping_spheres_merges() {
+        let csg = Csg::new(CsgOp::Union, sphere(0.0, 1.0), sphere(1.0, 1.0)).unwrap();
+        let ray = Ray::new(vec3(-5.0, 0.0, 0.0), Vec3::unit_x());
+
+        assert_spans(&csg.intervals(ray), &[(4.0, 7.0)]);
+    }
+
+    #[test]
+    fn intersection_of_overlapping_spheres() {
+        let csg = Csg::new(CsgOp::Intersection, sphere(0.0, 1.0), sphere(1.0, 1.0)).unwrap();
+        let ray = Ray::new(vec3(-5.0, 0.0, 0.0), Vec3::unit_x());
+
+        assert_spans(&csg.intervals(ray), &[(5.0, 6.0)]);
+    }
+
+    #[test]
+    fn ray_starting_inside_difference_hits_the_exit() {
+        let csg = Csg::new(CsgOp::Difference, sphere(0.0, 2.0), sphere(3.0, 1.5)).unwrap();
+        let ray = Ray::new(Vec3::zero(), Vec3::unit_x());
+
+        let hit = csg.intersection(ray, 0.0001, f32::INFINITY).unwrap();
+        assert!((hit.t - 1.5).abs() < 1e-4);
+        assert!(hit.normal.x() > 0.99);
+
+        // Going the other way it leaves through the left sphere
+        let ray = Ray::new(Vec3::zero(), -Vec3::unit_x());
+        let hit = csg.intersection(ray, 0.0001, f32::INFINITY).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-4);
+        assert!(hit.normal.x() < -0.99);
+    }
+
+    #[test]
+    fn ray_missing_subtracted_part() {
+        let csg = Csg::new(CsgOp::Difference, sphere(0.0, 1.0), sphere(0.0, 0.5)).unwrap();
+        let ray = Ray::new(vec3(-5.0, 0.75, 0.0), Vec3::unit_x());
+
+        assert_eq!(csg.intervals(ray).len(), 1);
+    }
+
+    /// Stands in for an open surface like a mesh, which has no inside
+    #[derive(Debug)]
+    struct Open;
+
+    impl Intersect for Open {
+        fn intersection(&self, _ray: Ray, _t_min: f32, _t_max: f32) -> Option<Hit> {
+            None
+        }
+
+        fn has_intersection(&self, _ray: Ray, _t_min: f32, _t_max: f32) -> bool {
+            false
+        }
+
+        fn bounds(&self) -> Option<Aabb> {
+            None
+        }
+    }
+
+    #[test]
+    fn open_children_are_rejected() {
+        assert!(Csg::new(CsgOp::Union, sphere(0.0, 1.0), Arc::new(Open)).is_err());
+        assert!(Csg::new(CsgOp::Difference, Arc::new(Open), sphere(0.0, 1.0)).is_err());
+    }
+}
@@ -1,20 +1,198 @@
-use crate::{material::Material, primitives::AABB, Hit, Intersect, Ray};
-use glam::{Quat, Vec3};
-use std::sync::Arc;
+use crate::{
+    material::Material,
+    medium::Medium,
+    primitives::{Aabb, Intervals},
+    Hit, Intersect, Ray, ShadingFrame,
+};
+use glam::{vec3, Quat, Vec3};
+use std::{cmp::Ordering, sync::Arc};
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Debug)]
 pub struct Transform {
     pub translation: Vec3,
     pub rotation: Quat,
     pub scale: Vec3,
 }
 
-#[derive(Clone)]
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::zero(),
+            rotation: Quat::identity(),
+            scale: Vec3::one(),
+        }
+    }
+}
+
+impl Transform {
+    /// Moves a world space ray into the local space of the transform.
+    /// The direction is not renormalized, so t means the same in both spaces.
+    pub fn ray_to_local(&self, ray: Ray) -> Ray {
+        let inverse = self.rotation.conjugate();
+
+        Ray::new(
+            inverse * (ray.origin - self.translation) / self.scale,
+            inverse * ray.direction / self.scale,
+        )
+        .at_time(ray.time)
+    }
+
+    pub fn point_to_world(&self, point: Vec3) -> Vec3 {
+        self.rotation * (point * self.scale) + self.translation
+    }
+
+    // Normals transform by the inverse transpose, which undoes the scale instead
+    pub fn normal_to_world(&self, normal: Vec3) -> Vec3 {
+        (self.rotation * (normal / self.scale)).normalize()
+    }
+
+    pub fn hit_to_world(&self, hit: &mut Hit) {
+        hit.point = self.point_to_world(hit.point);
+        hit.normal = self.normal_to_world(hit.normal);
+
+        // Tangents lie in the surface, so they transform like directions
+        let normal = self.normal_to_world(hit.shading.normal);
+        let tangent = self.rotation * (hit.shading.tangent * self.scale);
+        hit.shading = ShadingFrame::new(normal, tangent);
+    }
+
+    /// Bounds around all the corners of the local space bounds
+    pub fn bounds_to_world(&self, bounds: Aabb) -> Aabb {
+        (0..8).fold(
+            Aabb::new(Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |b, corner| {
+                let point = vec3(
+                    if corner & 1 == 0 {
+                        bounds.min.x()
+                    } else {
+                        bounds.max.x()
+                    },
+                    if corner & 2 == 0 {
+                        bounds.min.y()
+                    } else {
+                        bounds.max.y()
+                    },
+                    if corner & 4 == 0 {
+                        bounds.min.z()
+                    } else {
+                        bounds.max.z()
+                    },
+                );
+                b.point_union(self.point_to_world(point))
+            },
+        )
+    }
+
+    /// Interpolates towards other, lerping translation and scale and slerping rotation
+    pub fn interpolate(&self, other: &Transform, s: f32) -> Transform {
+        Transform {
+            translation: self.translation.lerp(other.translation, s),
+            // Slerp only comes out close to unit length, and the inverse rotation relies on it
+            rotation: self.rotation.slerp(other.rotation, s).normalize(),
+            scale: self.scale.lerp(other.scale, s),
+        }
+    }
+}
+
+/// A transform keyframed over time, held constant before the first and after the last keyframe
+#[derive(Clone, Debug)]
+pub struct AnimatedTransform {
+    keyframes: Vec<(f32, Transform)>,
+}
+
+impl AnimatedTransform {
+    pub fn new(mut keyframes: Vec<(f32, Transform)>) -> Self {
+        assert!(!keyframes.is_empty());
+        keyframes.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        Self { keyframes }
+    }
+
+    pub fn at(&self, time: f32) -> Transform {
+        let next = self.keyframes.iter().position(|(t, _)| *t > time);
+        match next {
+            Some(0) => self.keyframes[0].1,
+            Some(i) => {
+                let (t0, a) = &self.keyframes[i - 1];
+                let (t1, b) = &self.keyframes[i];
+                a.interpolate(b, (time - t0) / (t1 - t0))
+            }
+            None => self.keyframes[self.keyframes.len() - 1].1,
+        }
+    }
+
+    /// Bounds around the local space bounds over the whole animation.
+    /// Rotations sweep out arcs, so the interval between keyframes is sampled as well, and the
+    /// bounds are padded by how far the arcs can stray from the chords between the samples.
+    pub fn bounds_to_world(&self, bounds: Aabb) -> Aabb {
+        let steps = 16;
+        let mut world = self.keyframes[0].1.bounds_to_world(bounds);
+        let mut padding: f32 = 0.0;
+        for window in self.keyframes.windows(2) {
+            let (t0, t1) = (window[0].0, window[1].0);
+
+            // Farthest any point of the bounds gets from the origin once scaled. Scales are
+            // lerped, so this is largest at one of the keyframes.
+            let radius = [window[0].1.scale, window[1].1.scale]
+                .iter()
+                .flat_map(|&scale| {
+                    (0..8).map(move |corner| {
+                        let pick = |bit: usize, min: f32, max: f32| {
+                            if corner & bit == 0 {
+                                min
+                            } else {
+                                max
+                            }
+                        };
+                        let point = vec3(
+                            pick(1, bounds.min.x(), bounds.max.x()),
+                            pick(2, bounds.min.y(), bounds.max.y()),
+                            pick(4, bounds.min.z(), bounds.max.z()),
+                        );
+                        (point * scale).length()
+                    })
+                })
+                .fold(0.0, f32::max);
+
+            let mut previous = window[0].1;
+            for step in 1..=steps {
+                let time = t0 + (t1 - t0) * step as f32 / steps as f32;
+                let transform = self.at(time);
+                world = world.union(transform.bounds_to_world(bounds));
+
+                // Translation and scale move in straight lines between the samples, while a
+                // point radius away from the origin rotated by angle strays at most
+                // radius * angle / 2 from the chord
+                let cos_half_angle = previous.rotation.dot(transform.rotation).abs().min(1.0);
+                let angle = 2.0 * cos_half_angle.acos();
+                padding = padding.max(radius * angle / 2.0);
+                previous = transform;
+            }
+        }
+
+        let padding = Vec3::splat(padding);
+        Aabb::new(world.min - padding, world.max + padding)
+    }
+}
+
+impl From<Transform> for AnimatedTransform {
+    fn from(transform: Transform) -> Self {
+        Self::new(vec![(0.0, transform)])
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Instance {
     Receiver {
         primitive: Arc<dyn Intersect>,
         material: Arc<dyn Material>,
-        transform: Transform,
+        transform: AnimatedTransform,
+    },
+    /// A closed primitive filled with a participating medium, its surface is invisible
+    Volume {
+        boundary: Arc<dyn Intersect>,
+        medium: Arc<dyn Medium>,
+        transform: AnimatedTransform,
     },
     // Emitter {},
 }
@@ -23,68 +201,110 @@ impl Instance {
     pub fn receiver(
         primitive: Arc<dyn Intersect>,
         material: Arc<dyn Material>,
-        transform: Transform,
+        transform: impl Into<AnimatedTransform>,
     ) -> Self {
         Instance::Receiver {
             primitive,
             material,
-            transform,
+            transform: transform.into(),
         }
     }
-}
 
-// FIXME: Only does translation now
-impl Intersect for Instance {
-    fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
-        match self {
-            Instance::Receiver {
-                primitive,
-                material,
-                transform,
-            } => {
-                let ray = Ray::new(
-                    ray.origin - transform.translation,
-                    transform.rotation * ray.direction,
-                );
-                primitive.intersection(ray, t_min, t_max).map(|mut hit| {
-                    hit.material = Some(material.clone());
-                    hit.point += transform.translation;
-                    // hit.point = transform.rotation * hit.point;
-                    hit
-                })
-            }
+    pub fn volume(
+        boundary: Arc<dyn Intersect>,
+        medium: Arc<dyn Medium>,
+        transform: impl Into<AnimatedTransform>,
+    ) -> Self {
+        Instance::Volume {
+            boundary,
+            medium,
+            transform: transform.into(),
         }
     }
 
-    fn has_intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> bool {
+    fn primitive(&self) -> (&Arc<dyn Intersect>, &AnimatedTransform) {
         match self {
             Instance::Receiver {
                 primitive,
                 transform,
                 ..
-            } => {
-                let ray = Ray::new(
-                    ray.origin - transform.translation,
-                    // transform.rotation * ray.direction,
-                    ray.direction,
-                );
-                primitive.has_intersection(ray, t_min, t_max)
-            }
+            } => (primitive, transform),
+            Instance::Volume {
+                boundary,
+                transform,
+                ..
+            } => (boundary, transform),
         }
     }
 
-    fn bounds(&self) -> Option<AABB> {
+    /// The material of the instance, volumes have none
+    pub fn material(&self) -> Option<&Arc<dyn Material>> {
         match self {
-            Instance::Receiver {
-                primitive,
-                transform,
-                ..
-            } => primitive.bounds().map(|mut b| {
-                b.min += transform.translation;
-                b.max += transform.translation;
+            Instance::Receiver { material, .. } => Some(material),
+            Instance::Volume { .. } => None,
+        }
+    }
+
+    /// Describes the shape of the instance, where it is and what it is made of, to tell scenes
+    /// apart
+    pub fn fingerprint(&self) -> String {
+        let (primitive, transform) = self.primitive();
+        let made_of = match self {
+            Instance::Receiver { material, .. } => format!("{:?}", material),
+            Instance::Volume { medium, .. } => format!("{:?}", medium),
+        };
+
+        format!("{:x} {:?} {}", primitive.fingerprint(), transform, made_of)
+    }
 
-                b
-            }),
+    // Moves a hit on the primitive into world space and attaches what the instance is made of
+    fn finish_hit(&self, transform: &Transform, hit: &mut Hit) {
+        transform.hit_to_world(hit);
+        match self {
+            Instance::Receiver { material, .. } => hit.material = Some(material.clone()),
+            Instance::Volume { medium, .. } => hit.medium = Some(medium.clone()),
         }
     }
 }
+
+impl Intersect for Instance {
+    fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        let (primitive, transform) = self.primitive();
+        let transform = transform.at(ray.time);
+        primitive
+            .intersection(transform.ray_to_local(ray), t_min, t_max)
+            .map(|mut hit| {
+                self.finish_hit(&transform, &mut hit);
+                hit
+            })
+    }
+
+    fn has_intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> bool {
+        let (primitive, transform) = self.primitive();
+        let transform = transform.at(ray.time);
+        primitive.has_intersection(transform.ray_to_local(ray), t_min, t_max)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let (primitive, transform) = self.primitive();
+        primitive
+            .bounds()
+            .map(|bounds| transform.bounds_to_world(bounds))
+    }
+
+    fn closed(&self) -> bool {
+        self.primitive().0.closed()
+    }
+
+    fn intervals(&self, ray: Ray) -> Intervals {
+        let (primitive, transform) = self.primitive();
+        let transform = transform.at(ray.time);
+        let mut intervals = primitive.intervals(transform.ray_to_local(ray));
+        for interval in &mut intervals {
+            self.finish_hit(&transform, &mut interval.enter);
+            self.finish_hit(&transform, &mut interval.exit);
+        }
+
+        intervals
+    }
+}
@@ -0,0 +1,799 @@
+use crate::{
+    primitives::Aabb, sampler::hash_bytes, textures::Texture, Hit, Intersect, Ray, ShadingFrame,
+};
+use anyhow::{anyhow, ensure};
+use glam::Vec3;
+use std::collections::HashMap;
+
+/// Most triangles subdividing or tessellating a mesh may leave it with, past that it fails
+/// instead of running out of memory
+pub const MAX_TRIANGLES: usize = 1 << 24;
+
+/// A corner of a face, indexing into the positions, texture coordinates and normals of its mesh
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Corner {
+    pub position: u32,
+    pub uv: Option<u32>,
+    pub normal: Option<u32>,
+}
+
+/// Faces with any number of corners, as they are modelled
+#[derive(Clone, Debug, Default)]
+pub struct PolygonMesh {
+    pub positions: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub normals: Vec<Vec3>,
+    pub faces: Vec<Vec<Corner>>,
+}
+
+impl PolygonMesh {
+    /// Loads the vertices and faces of a Wavefront OBJ file, anything else in it is skipped
+    pub fn load_obj(path: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path, e))?;
+        Self::parse_obj(path, &text)
+    }
+
+    // Parses the text of an OBJ file, named path in errors
+    fn parse_obj(path: &str, text: &str) -> anyhow::Result<Self> {
+        let mut mesh = PolygonMesh::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut tokens = line.split_whitespace();
+            let error = |message: String| anyhow!("{}:{}: {}", path, number + 1, message);
+
+            let floats = |tokens: std::str::SplitWhitespace<'_>| {
+                tokens
+                    .map(|token| token.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| error(e.to_string()))
+            };
+            match tokens.next() {
+                Some("v") => match floats(tokens)?[..] {
+                    [x, y, z, ..] => mesh.positions.push(Vec3::new(x, y, z)),
+                    _ => return Err(error("Vertices need three coordinates".into())),
+                },
+                Some("vt") => match floats(tokens)?[..] {
+                    [u] => mesh.uvs.push((u, 0.0)),
+                    [u, v, ..] => mesh.uvs.push((u, v)),
+                    _ => return Err(error("Texture coordinates need a u".into())),
+                },
+                Some("vn") => match floats(tokens)?[..] {
+                    [x, y, z] => mesh.normals.push(Vec3::new(x, y, z).normalize()),
+                    _ => return Err(error("Normals need three coordinates".into())),
+                },
+                Some("f") => {
+                    let face = tokens
+                        .map(|token| mesh.corner(token))
+                        .collect::<anyhow::Result<Vec<_>>>()
+                        .map_err(|e| error(e.to_string()))?;
+                    if face.len() < 3 {
+                        return Err(error("Faces need at least three corners".into()));
+                    }
+                    mesh.faces.push(face);
+                }
+                _ => {}
+            }
+        }
+
+        ensure!(!mesh.faces.is_empty(), "{} has no faces", path);
+        Ok(mesh)
+    }
+
+    // Parses a corner written as position, position/uv, position//normal or position/uv/normal,
+    // counting from 1 or back from the last one read when negative
+    fn corner(&self, token: &str) -> anyhow::Result<Corner> {
+        fn index(index: &str, count: usize) -> anyhow::Result<u32> {
+            let index: i64 = index.parse()?;
+            let resolved = if index < 0 {
+                count as i64 + index
+            } else {
+                index - 1
+            };
+            ensure!(
+                (0..count as i64).contains(&resolved),
+                "Index {} is out of range",
+                index
+            );
+
+            Ok(resolved as u32)
+        }
+
+        let mut indices = token.split('/');
+        let position = index(indices.next().unwrap_or_default(), self.positions.len())?;
+        let uv = match indices.next() {
+            Some(uv) if !uv.is_empty() => Some(index(uv, self.uvs.len())?),
+            _ => None,
+        };
+        let normal = match indices.next() {
+            Some(normal) if !normal.is_empty() => Some(index(normal, self.normals.len())?),
+            _ => None,
+        };
+
+        Ok(Corner {
+            position,
+            uv,
+            normal,
+        })
+    }
+
+    /// Splits every face into a fan of triangles around its first corner. Texture coordinates
+    /// and normals are only kept when every corner has them.
+    pub fn triangulate(&self) -> TriangleMesh {
+        let corners = || self.faces.iter().flatten();
+        let has_uvs = corners().all(|corner| corner.uv.is_some());
+        let has_normals = corners().all(|corner| corner.normal.is_some());
+
+        let mut mesh = TriangleMesh {
+            positions: self.positions.clone(),
+            triangles: Vec::new(),
+            uvs: if has_uvs { Some(Vec::new()) } else { None },
+            normals: if has_normals { Some(Vec::new()) } else { None },
+        };
+        for face in &self.faces {
+            for i in 1..face.len() - 1 {
+                let triangle = [face[0], face[i], face[i + 1]];
+                mesh.triangles.push(triangle.map(|corner| corner.position));
+                if let Some(uvs) = &mut mesh.uvs {
+                    uvs.push(triangle.map(|corner| self.uvs[corner.uv.unwrap() as usize]));
+                }
+                if let Some(normals) = &mut mesh.normals {
+                    normals
+                        .push(triangle.map(|corner| self.normals[corner.normal.unwrap() as usize]));
+                }
+            }
+        }
+
+        mesh
+    }
+}
+
+/// Triangles sharing the positions of their vertices. Texture coordinates and normals are given
+/// per corner, so they can differ on either side of a seam or a hard edge.
+#[derive(Clone, Debug, Default)]
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3>,
+    /// Counter-clockwise seen from the front
+    pub triangles: Vec<[u32; 3]>,
+    pub uvs: Option<Vec<[(f32, f32); 3]>>,
+    pub normals: Option<Vec<[Vec3; 3]>>,
+}
+
+impl TriangleMesh {
+    /// Hash of the positions, triangles, texture coordinates and normals
+    pub fn fingerprint(&self) -> u64 {
+        let mut words = Vec::new();
+        let vectors = |vectors: &[Vec3]| -> Vec<u32> {
+            vectors
+                .iter()
+                .flat_map(|v| [v.x(), v.y(), v.z()])
+                .map(f32::to_bits)
+                .collect()
+        };
+        words.extend(vectors(&self.positions));
+        words.extend(self.triangles.iter().flatten());
+        words.push(self.uvs.is_some() as u32);
+        if let Some(uvs) = &self.uvs {
+            let uvs = uvs.iter().flatten().flat_map(|&(u, v)| [u, v]);
+            words.extend(uvs.map(f32::to_bits));
+        }
+        words.push(self.normals.is_some() as u32);
+        if let Some(normals) = &self.normals {
+            words.extend(vectors(normals.concat().as_slice()));
+        }
+
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        hash_bytes(&bytes)
+    }
+
+    /// Splits every triangle into four, level times
+    pub fn tessellate(&mut self, level: u32) -> anyhow::Result<()> {
+        let triangles = (self.triangles.len() as u64).saturating_mul(4u64.saturating_pow(level));
+        within_budget(triangles)?;
+
+        for _ in 0..level {
+            self.split_edges(|_, _| true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Splits triangles until none of their edges are longer than edge_length
+    pub fn tessellate_to_length(&mut self, edge_length: f32) -> anyhow::Result<()> {
+        assert!(edge_length > 0.0);
+
+        // None of the triangles can end up larger than an equilateral one with edge_length sides
+        let area: f32 = self
+            .triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.map(|i| self.positions[i as usize]);
+                0.5 * (b - a).cross(c - a).length()
+            })
+            .sum();
+        let largest = 3f32.sqrt() / 4.0 * edge_length * edge_length;
+        within_budget((area / largest) as u64)?;
+
+        while self.split_edges(|a, b| (a - b).length() > edge_length)? {}
+
+        Ok(())
+    }
+
+    // Splits the edges split picks at their midpoints, along with the triangles on both sides
+    // so they keep sharing their vertices. Returns whether any edge was split, and fails without
+    // splitting any if the triangles would go over MAX_TRIANGLES.
+    fn split_edges(&mut self, split: impl Fn(Vec3, Vec3) -> bool) -> anyhow::Result<bool> {
+        let edge = |a: u32, b: u32| (a.min(b), a.max(b));
+        let vertices = self.positions.len();
+        let mut midpoints = HashMap::new();
+        for triangle in &self.triangles {
+            for i in 0..3 {
+                let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                let (pa, pb) = (self.positions[a as usize], self.positions[b as usize]);
+                if !midpoints.contains_key(&edge(a, b)) && split(pa, pb) {
+                    midpoints.insert(edge(a, b), self.positions.len() as u32);
+                    self.positions.push(0.5 * (pa + pb));
+                }
+            }
+        }
+        if midpoints.is_empty() {
+            return Ok(false);
+        }
+
+        // A triangle comes out in one more piece than it has edges split
+        let pieces: usize = self
+            .triangles
+            .iter()
+            .map(|triangle| {
+                let split = (0..3)
+                    .filter(|&i| midpoints.contains_key(&edge(triangle[i], triangle[(i + 1) % 3])))
+                    .count();
+                split + 1
+            })
+            .sum();
+        if let Err(error) = within_budget(pieces as u64) {
+            self.positions.truncate(vertices);
+            return Err(error);
+        }
+
+        let mut triangles = Vec::with_capacity(self.triangles.len() * 4);
+        let mut uvs = self.uvs.as_ref().map(|_| Vec::new());
+        let mut normals = self.normals.as_ref().map(|_| Vec::new());
+        for (index, triangle) in self.triangles.iter().enumerate() {
+            // The corners and midpoints around the triangle, with the weights of the corners
+            // their attributes are blended from
+            let mut polygon = Vec::with_capacity(6);
+            for i in 0..3 {
+                let j = (i + 1) % 3;
+                let mut weights = [0.0; 3];
+                weights[i] = 1.0;
+                polygon.push((triangle[i], weights));
+                if let Some(&midpoint) = midpoints.get(&edge(triangle[i], triangle[j])) {
+                    let mut weights = [0.0; 3];
+                    weights[i] = 0.5;
+                    weights[j] = 0.5;
+                    polygon.push((midpoint, weights));
+                }
+            }
+
+            // Fully split triangles get a triangle in the middle, the others a fan around a
+            // midpoint
+            let pieces: Vec<[usize; 3]> = if polygon.len() == 6 {
+                vec![[0, 1, 5], [1, 2, 3], [5, 3, 4], [1, 3, 5]]
+            } else {
+                let n = polygon.len();
+                let first = (0..n).find(|&k| polygon[k].1.contains(&0.5)).unwrap_or(0);
+                (1..n - 1)
+                    .map(|k| [first, (first + k) % n, (first + k + 1) % n])
+                    .collect()
+            };
+
+            for piece in pieces {
+                triangles.push(piece.map(|k| polygon[k].0));
+                let weights = piece.map(|k| polygon[k].1);
+                if let (Some(uvs), Some(old)) = (&mut uvs, &self.uvs) {
+                    let [a, b, c] = old[index];
+                    uvs.push(weights.map(|[wa, wb, wc]| {
+                        (
+                            wa * a.0 + wb * b.0 + wc * c.0,
+                            wa * a.1 + wb * b.1 + wc * c.1,
+                        )
+                    }));
+                }
+                if let (Some(normals), Some(old)) = (&mut normals, &self.normals) {
+                    let [a, b, c] = old[index];
+                    normals
+                        .push(weights.map(|[wa, wb, wc]| (wa * a + wb * b + wc * c).normalize()));
+                }
+            }
+        }
+
+        self.triangles = triangles;
+        self.uvs = uvs;
+        self.normals = normals;
+        Ok(true)
+    }
+
+    /// Normals of the vertices, averaged over the triangles around them weighted by their area
+    pub fn vertex_normals(&self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::zero(); self.positions.len()];
+        for triangle in &self.triangles {
+            let [a, b, c] = triangle.map(|i| self.positions[i as usize]);
+            let normal = (b - a).cross(c - a);
+            for &i in triangle {
+                normals[i as usize] += normal;
+            }
+        }
+
+        normals
+            .into_iter()
+            .map(|normal| {
+                if normal.length_squared() > 0.0 {
+                    normal.normalize()
+                } else {
+                    normal
+                }
+            })
+            .collect()
+    }
+
+    /// Shades the whole mesh smoothly with the normals of its vertices
+    pub fn smooth_normals(&mut self) {
+        let normals = self.vertex_normals();
+        self.normals = Some(
+            self.triangles
+                .iter()
+                .map(|triangle| triangle.map(|i| normals[i as usize]))
+                .collect(),
+        );
+    }
+
+    /// Moves every vertex along its normal by the value of the texture at its texture
+    /// coordinates times scale, and shades the result smoothly. Vertices on seams take the
+    /// average over their corners so the surface does not tear apart.
+    pub fn displace(&mut self, texture: &dyn Texture, scale: f32) -> anyhow::Result<()> {
+        let uvs = self
+            .uvs
+            .as_ref()
+            .ok_or_else(|| anyhow!("Displaced meshes need texture coordinates"))?;
+
+        let mut heights = vec![(0.0, 0); self.positions.len()];
+        for (triangle, uvs) in self.triangles.iter().zip(uvs) {
+            for (&i, &(u, v)) in triangle.iter().zip(uvs) {
+                let (sum, count) = &mut heights[i as usize];
+                *sum += texture.scalar(u, v);
+                *count += 1;
+            }
+        }
+
+        let normals = self.vertex_normals();
+        for ((position, normal), (sum, count)) in
+            self.positions.iter_mut().zip(normals).zip(heights)
+        {
+            if count > 0 {
+                *position += normal * scale * sum / count as f32;
+            }
+        }
+        self.smooth_normals();
+
+        Ok(())
+    }
+
+    fn triangle_bounds(&self, triangle: usize) -> Aabb {
+        let [a, b, c] = self.triangles[triangle].map(|i| self.positions[i as usize]);
+        Aabb::new(a.min(b).min(c), a.max(b).max(c))
+    }
+}
+
+fn within_budget(triangles: u64) -> anyhow::Result<()> {
+    ensure!(
+        triangles <= MAX_TRIANGLES as u64,
+        "Tessellating the mesh would make more than {} triangles",
+        MAX_TRIANGLES
+    );
+
+    Ok(())
+}
+
+/// A triangle mesh with a bounding volume hierarchy of its own over the triangles
+#[derive(Debug)]
+pub struct Mesh {
+    mesh: TriangleMesh,
+    nodes: Vec<MeshNode>,
+}
+
+#[derive(Debug)]
+struct MeshNode {
+    bounds: Aabb,
+    /// First triangle of a leaf, or the second child of an interior node whose first child
+    /// comes right after it
+    start: u32,
+    /// Number of triangles in a leaf, zero for interior nodes
+    count: u32,
+}
+
+impl Mesh {
+    /// Most triangles in a leaf of the hierarchy
+    const LEAF_SIZE: usize = 4;
+
+    pub fn new(mesh: TriangleMesh) -> Self {
+        assert!(!mesh.triangles.is_empty());
+
+        let bounds: Vec<_> = (0..mesh.triangles.len())
+            .map(|i| mesh.triangle_bounds(i))
+            .collect();
+        let mut order: Vec<_> = (0..mesh.triangles.len()).collect();
+        let mut nodes = Vec::new();
+        Self::build(&mut nodes, &bounds, &mut order, 0);
+
+        // Sort the triangles into the order the leaves refer to them in
+        let mesh = TriangleMesh {
+            triangles: order.iter().map(|&i| mesh.triangles[i]).collect(),
+            uvs: mesh
+                .uvs
+                .as_ref()
+                .map(|uvs| order.iter().map(|&i| uvs[i]).collect()),
+            normals: mesh
+                .normals
+                .as_ref()
+                .map(|normals| order.iter().map(|&i| normals[i]).collect()),
+            positions: mesh.positions,
+        };
+
+        Self { mesh, nodes }
+    }
+
+    // Builds the node over the triangles in order by splitting them in half along the axis
+    // their centers spread out the most on, returning the index of the node
+    fn build(
+        nodes: &mut Vec<MeshNode>,
+        bounds: &[Aabb],
+        order: &mut [usize],
+        start: usize,
+    ) -> usize {
+        let index = nodes.len();
+        let empty = Aabb::new(Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY));
+        nodes.push(MeshNode {
+            bounds: order.iter().fold(empty, |b, &i| b.union(bounds[i])),
+            start: start as u32,
+            count: order.len() as u32,
+        });
+        if order.len() <= Self::LEAF_SIZE {
+            return index;
+        }
+
+        let center = |i: usize| 0.5 * (bounds[i].min + bounds[i].max);
+        let centers = order.iter().fold(empty, |b, &i| b.point_union(center(i)));
+        let extent = centers.max - centers.min;
+        let axis = (0..3).fold(0, |a, i| if extent[i] > extent[a] { i } else { a });
+
+        let mid = order.len() / 2;
+        order.select_nth_unstable_by(mid, |&a, &b| {
+            center(a)[axis]
+                .partial_cmp(&center(b)[axis])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let (left, right) = order.split_at_mut(mid);
+        Self::build(nodes, bounds, left, start);
+        let second = Self::build(nodes, bounds, right, start + mid);
+
+        nodes[index].start = second as u32;
+        nodes[index].count = 0;
+        index
+    }
+
+    // Finds the closest triangle the ray hits, or any one when any is set, returning the
+    // triangle, t and the barycentric coordinates of its second and third corner
+    fn closest(
+        &self,
+        ray: Ray,
+        t_min: f32,
+        t_max: f32,
+        any: bool,
+    ) -> Option<(usize, f32, f32, f32)> {
+        let mut closest = None;
+        let mut t_max = t_max;
+        let mut stack = vec![0];
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.bounds.clip(ray, t_min, t_max).is_none() {
+                continue;
+            }
+
+            if node.count == 0 {
+                stack.push(node.start as usize);
+                stack.push(index + 1);
+                continue;
+            }
+            let start = node.start as usize;
+            for triangle in start..start + node.count as usize {
+                if let Some((t, b1, b2)) = self.triangle_intersection(triangle, ray, t_min, t_max) {
+                    t_max = t;
+                    closest = Some((triangle, t, b1, b2));
+                    if any {
+                        return closest;
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    // Möller-Trumbore ray triangle intersection
+    fn triangle_intersection(
+        &self,
+        triangle: usize,
+        ray: Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, f32, f32)> {
+        let [a, b, c] = self.mesh.triangles[triangle].map(|i| self.mesh.positions[i as usize]);
+        let (e1, e2) = (b - a, c - a);
+        let p = ray.direction.cross(e2);
+        let determinant = e1.dot(p);
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+
+        let inverse = 1.0 / determinant;
+        let s = ray.origin - a;
+        let b1 = s.dot(p) * inverse;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let q = s.cross(e1);
+        let b2 = ray.direction.dot(q) * inverse;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+
+        let t = e2.dot(q) * inverse;
+        if t_min < t && t < t_max {
+            Some((t, b1, b2))
+        } else {
+            None
+        }
+    }
+}
+
+impl Intersect for Mesh {
+    fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        let (triangle, t, b1, b2) = self.closest(ray, t_min, t_max, false)?;
+        let weights = [1.0 - b1 - b2, b1, b2];
+        let [a, b, c] = self.mesh.triangles[triangle].map(|i| self.mesh.positions[i as usize]);
+        let (e1, e2) = (b - a, c - a);
+        let normal = e1.cross(e2).normalize();
+
+        // Without texture coordinates the barycentric coordinates stand in for them
+        let uvs = match &self.mesh.uvs {
+            Some(uvs) => uvs[triangle],
+            None => [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+        };
+        let uv = (
+            weights[0] * uvs[0].0 + weights[1] * uvs[1].0 + weights[2] * uvs[2].0,
+            weights[0] * uvs[0].1 + weights[1] * uvs[1].1 + weights[2] * uvs[2].1,
+        );
+
+        // The direction u grows in, from how the texture coordinates change along the edges
+        let (du1, dv1) = (uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1);
+        let (du2, dv2) = (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1);
+        let determinant = du1 * dv2 - dv1 * du2;
+        let tangent = if determinant.abs() > 1e-12 {
+            (dv2 * e1 - dv1 * e2) / determinant
+        } else {
+            Vec3::zero()
+        };
+
+        let mut hit = Hit::new(t, ray.point_at_parameter(t), normal);
+        hit.uv = uv;
+        let shading_normal = self.mesh.normals.as_ref().and_then(|normals| {
+            let [na, nb, nc] = normals[triangle];
+            let shading_normal = weights[0] * na + weights[1] * nb + weights[2] * nc;
+            if shading_normal.length_squared() > 1e-12 {
+                Some(shading_normal.normalize())
+            } else {
+                None
+            }
+        });
+        match shading_normal {
+            // The surface faces the same way as the normals it is shaded with
+            Some(shading_normal) => {
+                if shading_normal.dot(normal) < 0.0 {
+                    hit.normal = -normal;
+                }
+                hit.shading = ShadingFrame::new(shading_normal, tangent);
+            }
+            None => hit.shading = ShadingFrame::new(normal, tangent),
+        }
+
+        Some(hit)
+    }
+
+    fn has_intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> bool {
+        self.closest(ray, t_min, t_max, true).is_some()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    // Describing every triangle would take far longer than hashing them
+    fn fingerprint(&self) -> u64 {
+        self.mesh.fingerprint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::textures::UniformTexture;
+    use glam::vec3;
+
+    const QUAD: &str = "
+        v 0 0 0
+        v 1 0 0
+        v 1 1 0
+        v 0 1 0
+        vt 0 0
+        vt 1 0
+        vt 1 1
+        vt 0 1
+        vn 0 0 2
+    ";
+
+    fn corner(position: u32, uv: Option<u32>, normal: Option<u32>) -> Corner {
+        Corner {
+            position,
+            uv,
+            normal,
+        }
+    }
+
+    #[test]
+    fn corner_forms() {
+        let text = format!(
+            "{}
+            f 1 2 3
+            f 1/1 2/2 3/3
+            f 1//1 2//1 3//1
+            f 1/1/1 2/2/1 3/3/1 4/4/1 # a quad
+            ",
+            QUAD
+        );
+        let mesh = PolygonMesh::parse_obj("quad.obj", &text).unwrap();
+
+        assert_eq!(mesh.positions[2], vec3(1.0, 1.0, 0.0));
+        assert_eq!(mesh.uvs[3], (0.0, 1.0));
+        assert_eq!(mesh.normals[0], vec3(0.0, 0.0, 1.0));
+        assert_eq!(mesh.faces.len(), 4);
+        assert_eq!(mesh.faces[0][1], corner(1, None, None));
+        assert_eq!(mesh.faces[1][1], corner(1, Some(1), None));
+        assert_eq!(mesh.faces[2][1], corner(1, None, Some(0)));
+        assert_eq!(mesh.faces[3][3], corner(3, Some(3), Some(0)));
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_read() {
+        let text = format!(
+            "{}
+            f -4/-4/-1 -3/-3/-1 -2/-2/-1
+            v 2 0 0
+            f -1 -4 -3
+            ",
+            QUAD
+        );
+        let mesh = PolygonMesh::parse_obj("quad.obj", &text).unwrap();
+
+        assert_eq!(
+            mesh.faces[0],
+            vec![
+                corner(0, Some(0), Some(0)),
+                corner(1, Some(1), Some(0)),
+                corner(2, Some(2), Some(0)),
+            ]
+        );
+        let positions: Vec<_> = mesh.faces[1].iter().map(|c| c.position).collect();
+        assert_eq!(positions, vec![4, 1, 2]);
+    }
+
+    #[test]
+    fn indices_out_of_range() {
+        for face in &[
+            "f 1 2 5",
+            "f 0 1 2",
+            "f -5 1 2",
+            "f 1/5 2/1 3/1",
+            "f 1//2 2//1 3//1",
+        ] {
+            let text = format!("{}\n{}", QUAD, face);
+            let error = PolygonMesh::parse_obj("quad.obj", &text).unwrap_err();
+            assert!(error.to_string().starts_with("quad.obj:12:"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn faces_need_three_corners() {
+        let text = format!("{}\nf 1 2", QUAD);
+        assert!(PolygonMesh::parse_obj("quad.obj", &text).is_err());
+        assert!(PolygonMesh::parse_obj("quad.obj", QUAD).is_err());
+    }
+
+    #[test]
+    fn triangulate_fans_around_the_first_corner() {
+        let text = format!("{}\nf 1/1 2/2 3/3 4/4", QUAD);
+        let mesh = PolygonMesh::parse_obj("quad.obj", &text)
+            .unwrap()
+            .triangulate();
+
+        assert_eq!(mesh.triangles, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(mesh.uvs.unwrap()[1], [(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)]);
+        // Not every corner has a normal
+        assert!(mesh.normals.is_none());
+    }
+
+    #[test]
+    fn displace_moves_vertices_along_their_normals() {
+        let text = format!("{}\nf 1/1 2/2 3/3 4/4", QUAD);
+        let mut mesh = PolygonMesh::parse_obj("quad.obj", &text)
+            .unwrap()
+            .triangulate();
+        mesh.displace(&UniformTexture::new(Vec3::splat(0.5)), 2.0)
+            .unwrap();
+
+        for (position, original) in mesh.positions.iter().zip(&[
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(1.0, 1.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+        ]) {
+            assert!((*position - (*original + Vec3::unit_z())).length() < 1e-6);
+        }
+        assert!(mesh.normals.is_some());
+
+        mesh.uvs = None;
+        assert!(mesh
+            .displace(&UniformTexture::new(Vec3::one()), 1.0)
+            .is_err());
+    }
+
+    #[test]
+    fn fingerprint_changes_with_the_geometry() {
+        let text = format!("{}\nf 1/1 2/2 3/3 4/4", QUAD);
+        let mesh = PolygonMesh::parse_obj("quad.obj", &text)
+            .unwrap()
+            .triangulate();
+        assert_eq!(mesh.fingerprint(), mesh.clone().fingerprint());
+
+        let mut moved = mesh.clone();
+        moved.positions[2] += vec3(0.0, 0.0, 0.001);
+        assert_ne!(mesh.fingerprint(), moved.fingerprint());
+
+        let mut without_uvs = mesh.clone();
+        without_uvs.uvs = None;
+        assert_ne!(mesh.fingerprint(), without_uvs.fingerprint());
+    }
+
+    #[test]
+    fn tessellating_past_the_budget_fails() {
+        let text = format!("{}\nf 1/1 2/2 3/3 4/4", QUAD);
+        let mut mesh = PolygonMesh::parse_obj("quad.obj", &text)
+            .unwrap()
+            .triangulate();
+        mesh.tessellate(2).unwrap();
+        assert_eq!(mesh.triangles.len(), 32);
+
+        let error = mesh.tessellate_to_length(1e-6).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Tessellating the mesh would make more than {} triangles",
+                MAX_TRIANGLES
+            )
+        );
+        assert!(mesh.tessellate(11).is_err());
+        assert!(mesh.tessellate(u32::MAX).is_err());
+        assert_eq!(mesh.triangles.len(), 32);
+    }
+}
@@ -1,17 +1,28 @@
 //! This module is full of primitives that all impl Intersection
 
 mod aabb;
+mod csg;
 mod instance;
+mod mesh;
+mod sdf;
 mod sphere;
+mod subdivision;
 
 pub use aabb::*;
+pub use csg::*;
 pub use instance::*;
+pub use mesh::*;
+pub use sdf::*;
 pub use sphere::*;
+pub use subdivision::*;
 
-use crate::ray::{Hit, Ray};
+use crate::{
+    ray::{Hit, Ray},
+    sampler::hash_bytes,
+};
 
 /// Computes whether a ray intersects a primitive
-pub trait Intersect: Send + Sync {
+pub trait Intersect: std::fmt::Debug + Send + Sync {
     /// Computes the intersection between the ray and the primitive
     fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit>;
 
@@ -20,5 +31,23 @@ pub trait Intersect: Send + Sync {
     fn has_intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> bool;
 
     /// Generate a bounds for the primitive
-    fn bounds(&self) -> Option<AABB>;
+    fn bounds(&self) -> Option<Aabb>;
+
+    /// Hash of the shape of the primitive, to tell scenes apart. By default the description of
+    /// the primitive is hashed, which large primitives should replace with hashing their data.
+    fn fingerprint(&self) -> u64 {
+        hash_bytes(format!("{:?}", self).as_bytes())
+    }
+
+    /// Whether the primitive bounds a solid with an inside, which CSG needs from its children.
+    /// Primitives that return true must also implement "intervals".
+    fn closed(&self) -> bool {
+        false
+    }
+
+    /// Computes every interval along the whole ray line where it is inside the primitive,
+    /// sorted by t. Only closed primitives have an inside, so by default there are none.
+    fn intervals(&self, _ray: Ray) -> Intervals {
+        Intervals::new()
+    }
 }
@@ -0,0 +1,514 @@
+use crate::{
+    primitives::{Aabb, Interval, Intervals},
+    Hit, Intersect, Ray,
+};
+use glam::{vec2, vec3, Vec3};
+
+/// A node in a signed distance function expression tree.
+/// Leaves are shapes centered on the origin, interior nodes combine or deform their children.
+#[derive(Clone, Debug)]
+pub enum SdfNode {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: Vec3,
+    },
+    /// A box with its edges rounded off by radius, staying inside the half extents
+    RoundBox {
+        half_extents: Vec3,
+        radius: f32,
+    },
+    /// A torus lying in the xz-plane
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    /// A line segment from a to b swept by a sphere
+    Capsule {
+        a: Vec3,
+        b: Vec3,
+        radius: f32,
+    },
+    Translate {
+        offset: Vec3,
+        node: Box<SdfNode>,
+    },
+    /// Uniform scale, non-uniform scaling would break the distance bound
+    Scale {
+        factor: f32,
+        node: Box<SdfNode>,
+    },
+    Union(Box<SdfNode>, Box<SdfNode>),
+    Intersection(Box<SdfNode>, Box<SdfNode>),
+    /// Carves the second node out of the first
+    Subtraction(Box<SdfNode>, Box<SdfNode>),
+    /// Union blended over a distance of k with a polynomial smooth minimum
+    SmoothUnion {
+        a: Box<SdfNode>,
+        b: Box<SdfNode>,
+        k: f32,
+    },
+    SmoothIntersection {
+        a: Box<SdfNode>,
+        b: Box<SdfNode>,
+        k: f32,
+    },
+    SmoothSubtraction {
+        a: Box<SdfNode>,
+        b: Box<SdfNode>,
+        k: f32,
+    },
+    /// Twists the node around the y-axis by rate radians per unit of height.
+    /// This is not distance preserving, so lower the step scale of the Sdf when using it.
+    Twist {
+        rate: f32,
+        node: Box<SdfNode>,
+    },
+    /// Repeats the node on a grid with the given period, limit copies out from the origin per axis.
+    /// The node should fit inside a single cell for the distance to stay correct.
+    Repeat {
+        period: Vec3,
+        limit: Vec3,
+        node: Box<SdfNode>,
+    },
+}
+
+impl SdfNode {
+    pub fn sphere(radius: f32) -> Self {
+        SdfNode::Sphere { radius }
+    }
+
+    pub fn cuboid(half_extents: Vec3) -> Self {
+        SdfNode::Box { half_extents }
+    }
+
+    pub fn round_box(half_extents: Vec3, radius: f32) -> Self {
+        SdfNode::RoundBox {
+            half_extents,
+            radius,
+        }
+    }
+
+    pub fn torus(major_radius: f32, minor_radius: f32) -> Self {
+        SdfNode::Torus {
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    pub fn capsule(a: Vec3, b: Vec3, radius: f32) -> Self {
+        SdfNode::Capsule { a, b, radius }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        SdfNode::Translate {
+            offset,
+            node: Box::new(self),
+        }
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        SdfNode::Scale {
+            factor,
+            node: Box::new(self),
+        }
+    }
+
+    pub fn union(self, other: SdfNode) -> Self {
+        SdfNode::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: SdfNode) -> Self {
+        SdfNode::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn subtraction(self, other: SdfNode) -> Self {
+        SdfNode::Subtraction(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: SdfNode, k: f32) -> Self {
+        SdfNode::SmoothUnion {
+            a: Box::new(self),
+            b: Box::new(other),
+            k,
+        }
+    }
+
+    pub fn smooth_intersection(self, other: SdfNode, k: f32) -> Self {
+        SdfNode::SmoothIntersection {
+            a: Box::new(self),
+            b: Box::new(other),
+            k,
+        }
+    }
+
+    pub fn smooth_subtraction(self, other: SdfNode, k: f32) -> Self {
+        SdfNode::SmoothSubtraction {
+            a: Box::new(self),
+            b: Box::new(other),
+            k,
+        }
+    }
+
+    pub fn twist(self, rate: f32) -> Self {
+        SdfNode::Twist {
+            rate,
+            node: Box::new(self),
+        }
+    }
+
+    pub fn repeat(self, period: Vec3, limit: Vec3) -> Self {
+        SdfNode::Repeat {
+            period,
+            limit,
+            node: Box::new(self),
+        }
+    }
+
+    /// Signed distance from p to the surface, negative inside
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match self {
+            SdfNode::Sphere { radius } => p.length() - radius,
+            SdfNode::Box { half_extents } => {
+                let q = p.abs() - *half_extents;
+                q.max(Vec3::zero()).length() + q.max_element().min(0.0)
+            }
+            SdfNode::RoundBox {
+                half_extents,
+                radius,
+            } => {
+                let q = p.abs() - *half_extents + Vec3::splat(*radius);
+                q.max(Vec3::zero()).length() + q.max_element().min(0.0) - radius
+            }
+            SdfNode::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let q = vec2(vec2(p.x(), p.z()).length() - major_radius, p.y());
+                q.length() - minor_radius
+            }
+            SdfNode::Capsule { a, b, radius } => {
+                let pa = p - *a;
+                let ba = *b - *a;
+                let h = (pa.dot(ba) / ba.dot(ba)).clamp(0.0, 1.0);
+                (pa - ba * h).length() - radius
+            }
+            SdfNode::Translate { offset, node } => node.distance(p - *offset),
+            SdfNode::Scale { factor, node } => node.distance(p / *factor) * factor,
+            SdfNode::Union(a, b) => a.distance(p).min(b.distance(p)),
+            SdfNode::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            SdfNode::Subtraction(a, b) => a.distance(p).max(-b.distance(p)),
+            SdfNode::SmoothUnion { a, b, k } => smooth_min(a.distance(p), b.distance(p), *k),
+            SdfNode::SmoothIntersection { a, b, k } => {
+                -smooth_min(-a.distance(p), -b.distance(p), *k)
+            }
+            SdfNode::SmoothSubtraction { a, b, k } => {
+                -smooth_min(-a.distance(p), b.distance(p), *k)
+            }
+            SdfNode::Twist { rate, node } => {
+                let (sin, cos) = (rate * p.y()).sin_cos();
+                let q = vec3(cos * p.x() - sin * p.z(), p.y(), sin * p.x() + cos * p.z());
+                node.distance(q)
+            }
+            SdfNode::Repeat {
+                period,
+                limit,
+                node,
+            } => {
+                let cell = (p / *period).round().max(-*limit).min(*limit);
+                node.distance(p - *period * cell)
+            }
+        }
+    }
+
+    /// A conservative bounding box of the surface
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            SdfNode::Sphere { radius } => Aabb::new(-Vec3::splat(*radius), Vec3::splat(*radius)),
+            SdfNode::Box { half_extents } | SdfNode::RoundBox { half_extents, .. } => {
+                Aabb::new(-*half_extents, *half_extents)
+            }
+            SdfNode::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let r = major_radius + minor_radius;
+                Aabb::new(vec3(-r, -minor_radius, -r), vec3(r, *minor_radius, r))
+            }
+            SdfNode::Capsule { a, b, radius } => {
+                let r = Vec3::splat(*radius);
+                Aabb::new(*a - r, *a + r).union(Aabb::new(*b - r, *b + r))
+            }
+            SdfNode::Translate { offset, node } => {
+                let b = node.bounds();
+                Aabb::new(b.min + *offset, b.max + *offset)
+            }
+            SdfNode::Scale { factor, node } => {
+                let b = node.bounds();
+                Aabb::new(b.min * *factor, b.max * *factor)
+            }
+            SdfNode::Union(a, b) => a.bounds().union(b.bounds()),
+            SdfNode::Intersection(a, b) | SdfNode::SmoothIntersection { a, b, .. } => {
+                let (a, b) = (a.bounds(), b.bounds());
+                Aabb::new(a.min.max(b.min), a.max.min(b.max))
+            }
+            SdfNode::Subtraction(a, _) | SdfNode::SmoothSubtraction { a, .. } => a.bounds(),
+            // The smooth minimum is at most k / 4 below the regular minimum
+            SdfNode::SmoothUnion { a, b, k } => {
+                let b = a.bounds().union(b.bounds());
+                let k = Vec3::splat(k / 4.0);
+                Aabb::new(b.min - k, b.max + k)
+            }
+            // Twisting around the y-axis stays within the cylinder around the child bounds
+            SdfNode::Twist { node, .. } => {
+                let b = node.bounds();
+                let x = b.min.x().abs().max(b.max.x().abs());
+                let z = b.min.z().abs().max(b.max.z().abs());
+                let r = (x * x + z * z).sqrt();
+                Aabb::new(vec3(-r, b.min.y(), -r), vec3(r, b.max.y(), r))
+            }
+            SdfNode::Repeat {
+                period,
+                limit,
+                node,
+            } => {
+                let b = node.bounds();
+                let extent = period.abs() * *limit;
+                Aabb::new(b.min - extent, b.max + extent)
+            }
+        }
+    }
+}
+
+// Polynomial smooth minimum by Inigo Quilez
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    let h = (k - (a - b).abs()).max(0.0) / k;
+    a.min(b) - h * h * k * 0.25
+}
+
+/// A primitive defined by a signed distance function and rendered by sphere tracing
+#[derive(Clone, Debug)]
+pub struct Sdf {
+    root: SdfNode,
+    bounds: Aabb,
+    /// Max number of steps taken along a ray before giving up
+    max_steps: u32,
+    /// Distance to the surface at which we consider the ray to have hit it
+    epsilon: f32,
+    /// Fraction of the distance bound to step, lower it for deforming operators like twist
+    step_scale: f32,
+}
+
+impl Sdf {
+    pub fn new(root: SdfNode) -> Self {
+        let bounds = root.bounds();
+
+        Self {
+            root,
+            bounds,
+            max_steps: 256,
+            epsilon: 0.0001,
+            step_scale: 1.0,
+        }
+    }
+
+    pub fn with_max_steps(mut self, max_steps: u32) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn with_epsilon(mut self, epsilon: f32) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
+    pub fn with_step_scale(mut self, step_scale: f32) -> Self {
+        self.step_scale = step_scale;
+        self
+    }
+
+    /// Normal from the gradient of the distance function by central differences
+    pub fn normal(&self, p: Vec3) -> Vec3 {
+        let h = self.epsilon;
+        let dx = vec3(h, 0.0, 0.0);
+        let dy = vec3(0.0, h, 0.0);
+        let dz = vec3(0.0, 0.0, h);
+
+        vec3(
+            self.root.distance(p + dx) - self.root.distance(p - dx),
+            self.root.distance(p + dy) - self.root.distance(p - dy),
+            self.root.distance(p + dz) - self.root.distance(p - dz),
+        )
+        .normalize()
+    }
+
+    /// Marches along the ray from t_min and returns the parameter of the first surface crossing
+    fn march(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<f32> {
+        let (t, t_far) = self.bounds.clip(ray, t_min, t_max)?;
+
+        // Which side of the surface we are marching on, so rays starting inside work as well
+        let d = self.root.distance(ray.point_at_parameter(t));
+        let side = if d.abs() >= self.epsilon {
+            d.signum()
+        } else if t > t_min {
+            // The ray entered the bounds right on the surface
+            return Some(t);
+        } else {
+            // Starting on the surface, so let the direction decide which side we are leaving to
+            let normal = self.normal(ray.point_at_parameter(t));
+            if ray.direction.dot(normal) > 0.0 {
+                1.0
+            } else {
+                -1.0
+            }
+        };
+
+        self.march_side(ray, t, t_far, side)
+    }
+
+    /// Marches along the ray on the given side of the surface until it crosses it
+    fn march_side(&self, ray: Ray, mut t: f32, t_far: f32, side: f32) -> Option<f32> {
+        let speed = ray.direction.length();
+
+        // Don't report the surface we started on before we have moved away from it,
+        // unless we have already ended up on the other side
+        let mut escaped = false;
+        for _ in 0..self.max_steps {
+            let distance = side * self.root.distance(ray.point_at_parameter(t));
+
+            if distance < -self.epsilon || (escaped && distance < self.epsilon) {
+                return Some(t);
+            }
+            escaped |= distance >= self.epsilon;
+
+            t += self.step_scale * distance.max(self.epsilon) / speed;
+            if t > t_far {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    fn hit(&self, ray: Ray, t: f32) -> Hit {
+        let point = ray.point_at_parameter(t);
+
+        Hit::new(t, point, self.normal(point))
+    }
+}
+
+impl Intersect for Sdf {
+    fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        self.march(ray, t_min, t_max).map(|t| self.hit(ray, t))
+    }
+
+    fn has_intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> bool {
+        self.march(ray, t_min, t_max).is_some()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn closed(&self) -> bool {
+        true
+    }
+
+    fn intervals(&self, ray: Ray) -> Intervals {
+        let mut intervals = Intervals::new();
+        let (mut t, t_far) = match self.bounds.clip(ray, f32::MIN, f32::MAX) {
+            Some(range) => range,
+            None => return intervals,
+        };
+
+        // The bounds are conservative, so marching starts outside and alternates sides
+        while let Some(enter) = self.march_side(ray, t, t_far, 1.0) {
+            let exit = self.march_side(ray, enter, t_far, -1.0).unwrap_or(t_far);
+            intervals.push(Interval {
+                enter: self.hit(ray, enter),
+                exit: self.hit(ray, exit),
+            });
+            t = exit;
+        }
+
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::vec3;
+
+    fn contains(bounds: Aabb, p: Vec3) -> bool {
+        let tolerance = Vec3::splat(1e-4);
+        p.cmpge(bounds.min - tolerance).all() && p.cmple(bounds.max + tolerance).all()
+    }
+
+    #[test]
+    fn sphere_and_box_distances() {
+        let sphere = SdfNode::sphere(1.0);
+        assert!((sphere.distance(vec3(3.0, 0.0, 0.0)) - 2.0).abs() < 1e-6);
+        assert!((sphere.distance(Vec3::zero()) + 1.0).abs() < 1e-6);
+
+        let cuboid = SdfNode::cuboid(vec3(1.0, 2.0, 3.0));
+        assert!((cuboid.distance(vec3(3.0, 0.0, 0.0)) - 2.0).abs() < 1e-6);
+        // Outside a corner the closest point is the corner itself
+        assert!((cuboid.distance(vec3(2.0, 3.0, 0.0)) - 2.0f32.sqrt()).abs() < 1e-6);
+        // Inside the distance is to the closest face
+        assert!((cuboid.distance(Vec3::zero()) + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bounds_enclose_surface() {
+        let nodes = [
+            SdfNode::torus(1.0, 0.25),
+            SdfNode::capsule(vec3(-1.0, 0.0, 0.0), vec3(0.5, 1.0, 0.0), 0.3),
+            SdfNode::cuboid(vec3(0.5, 1.0, 0.25)).twist(1.0),
+            SdfNode::sphere(0.5)
+                .translate(vec3(0.5, 0.0, 0.0))
+                .smooth_union(SdfNode::sphere(0.5).translate(vec3(-0.5, 0.0, 0.0)), 0.5),
+            SdfNode::sphere(0.2).repeat(Vec3::splat(0.5), vec3(2.0, 1.0, 0.0)),
+            SdfNode::round_box(Vec3::splat(0.5), 0.1).scale(1.5),
+        ];
+
+        for node in &nodes {
+            let bounds = node.bounds();
+            let n = 40;
+            for i in 0..=n {
+                for j in 0..=n {
+                    for k in 0..=n {
+                        let p =
+                            vec3(i as f32, j as f32, k as f32) / n as f32 * 6.0 - Vec3::splat(3.0);
+                        assert!(
+                            node.distance(p) > 0.0 || contains(bounds, p),
+                            "{:?} is inside {:?} but outside its bounds",
+                            p,
+                            node
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_tracing_hits_and_misses() {
+        let sdf = Sdf::new(SdfNode::sphere(1.0));
+
+        let hit = sdf
+            .intersection(
+                Ray::new(vec3(-5.0, 0.0, 0.0), Vec3::unit_x()),
+                0.0,
+                f32::MAX,
+            )
+            .unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-3);
+        assert!(hit.normal.x() < -0.99);
+
+        let miss = Ray::new(vec3(-5.0, 1.5, 0.0), Vec3::unit_x());
+        assert!(sdf.intersection(miss, 0.0, f32::MAX).is_none());
+        assert!(!sdf.has_intersection(miss, 0.0, f32::MAX));
+    }
+}
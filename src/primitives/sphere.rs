@@ -1,5 +1,9 @@
-use crate::{primitives::AABB, Hit, Intersect, Ray};
+use crate::{
+    primitives::{Aabb, Interval, Intervals},
+    Hit, Intersect, Ray,
+};
 use glam::{vec3, Vec3};
+use std::f32::consts::PI;
 
 #[derive(Clone, Debug)]
 pub struct Sphere {
@@ -13,6 +17,22 @@ impl Sphere {
     }
 }
 
+impl Sphere {
+    fn hit(&self, ray: Ray, t: f32) -> Hit {
+        let point = ray.point_at_parameter(t);
+
+        let normal = (point - self.center) / self.radius;
+
+        // Longitude around the y axis and latitude from the bottom pole
+        let phi = (-normal.z()).atan2(normal.x()) + PI;
+        let theta = (-normal.y()).clamp(-1.0, 1.0).acos();
+        let uv = (phi / (2.0 * PI), theta / PI);
+        let tangent = vec3(normal.z(), 0.0, -normal.x());
+
+        Hit::new(t, point, normal).with_uv(uv, tangent)
+    }
+}
+
 impl Intersect for Sphere {
     fn intersection(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<Hit> {
         let oc = ray.origin - self.center;
@@ -27,14 +47,7 @@ impl Intersect for Sphere {
 
             for &t in &[t_1, t_2] {
                 if t_min < t && t < t_max {
-                    let point = ray.point_at_parameter(t);
-
-                    return Some(Hit {
-                        t,
-                        point,
-                        normal: (point - self.center) / self.radius,
-                        material: None,
-                    });
+                    return Some(self.hit(ray, t));
                 }
             }
         }
@@ -52,10 +65,35 @@ impl Intersect for Sphere {
         discriminant > 0.0
     }
 
-    fn bounds(&self) -> Option<AABB> {
-        Some(AABB::new(
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::new(
             self.center - vec3(self.radius, self.radius, self.radius),
             self.center + vec3(self.radius, self.radius, self.radius),
         ))
     }
+
+    fn closed(&self) -> bool {
+        true
+    }
+
+    fn intervals(&self, ray: Ray) -> Intervals {
+        let oc = ray.origin - self.center;
+        let a = ray.direction.dot(ray.direction);
+        let b = oc.dot(ray.direction);
+        let c = oc.dot(oc) - self.radius * self.radius;
+        let discriminant = b * b - a * c;
+
+        let mut intervals = Intervals::new();
+        if discriminant > 0.0 {
+            let t_1 = (-b - f32::sqrt(discriminant)) / a;
+            let t_2 = (-b + f32::sqrt(discriminant)) / a;
+
+            intervals.push(Interval {
+                enter: self.hit(ray, t_1),
+                exit: self.hit(ray, t_2),
+            });
+        }
+
+        intervals
+    }
 }
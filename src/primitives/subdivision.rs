@@ -0,0 +1,476 @@
+use crate::primitives::{Corner, PolygonMesh, MAX_TRIANGLES};
+use anyhow::ensure;
+use glam::Vec3;
+use std::collections::{HashMap, HashSet};
+
+/// An edge kept sharp for as many levels of subdivision as its sharpness, a fraction of a level
+/// blends it into the smooth surface
+#[derive(Clone, Copy, Debug)]
+pub struct Crease {
+    /// Indices of the positions at either end
+    pub vertices: [u32; 2],
+    pub sharpness: f32,
+}
+
+struct Edge {
+    /// Faces on either side of the edge, more than two when the mesh is not manifold
+    faces: Vec<usize>,
+    /// Infinite on boundaries and edges shared by more than two faces
+    sharpness: f32,
+    /// Index of the point the edge is split at in the refined mesh
+    point: u32,
+}
+
+// Edges are the same whichever way around the faces on either side go
+fn edge(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+impl PolygonMesh {
+    /// Refines the faces with Catmull-Clark subdivision level times, into quads closing in on a
+    /// smooth surface. Boundaries and edges shared by more than two faces stay sharp and the
+    /// corners of the boundary stay in place, following "Subdivision Surfaces in Character
+    /// Animation" by DeRose et al. for the creases. Texture coordinates are interpolated
+    /// linearly over every face and normals are left out.
+    pub fn subdivide(&self, level: u32, creases: &[Crease]) -> anyhow::Result<PolygonMesh> {
+        let edges: HashSet<_> = self
+            .faces
+            .iter()
+            .flat_map(|face| {
+                (0..face.len())
+                    .map(move |i| edge(face[i].position, face[(i + 1) % face.len()].position))
+            })
+            .collect();
+        for crease in creases {
+            let [a, b] = crease.vertices;
+            ensure!(
+                edges.contains(&edge(a, b)),
+                "Crease between vertices {} and {} is not an edge of the mesh",
+                a + 1,
+                b + 1
+            );
+        }
+
+        // Every corner of a face becomes a quad on the first level, every quad four on the next,
+        // and the quads are split into two triangles in the end
+        if level > 0 {
+            let corners: u64 = self.faces.iter().map(|face| face.len() as u64).sum();
+            let triangles = corners
+                .saturating_mul(2)
+                .saturating_mul(4u64.saturating_pow(level - 1));
+            ensure!(
+                triangles <= MAX_TRIANGLES as u64,
+                "Subdividing the mesh {} times would make more than {} triangles",
+                level,
+                MAX_TRIANGLES
+            );
+        }
+
+        let mut mesh = self.clone();
+        let mut creases = creases.to_vec();
+        for _ in 0..level {
+            let (refined, refined_creases) = mesh.refine(&creases);
+            mesh = refined;
+            creases = refined_creases;
+        }
+
+        Ok(mesh)
+    }
+
+    // A single level of subdivision. The refined mesh keeps the vertices at their indices,
+    // followed by a point on every edge and then one in the middle of every face.
+    fn refine(&self, creases: &[Crease]) -> (PolygonMesh, Vec<Crease>) {
+        let vertex_count = self.positions.len();
+        let position = |corner: &Corner| self.positions[corner.position as usize];
+        let face_points: Vec<Vec3> = self
+            .faces
+            .iter()
+            .map(|face| {
+                face.iter().map(position).fold(Vec3::zero(), |a, b| a + b) / face.len() as f32
+            })
+            .collect();
+
+        // Edges in the order of their points, so the sums below come out the same every time
+        let mut edges = HashMap::new();
+        let mut keys = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            for i in 0..face.len() {
+                let key = edge(face[i].position, face[(i + 1) % face.len()].position);
+                let point = (vertex_count + edges.len()) as u32;
+                if !edges.contains_key(&key) {
+                    keys.push(key);
+                }
+                edges
+                    .entry(key)
+                    .or_insert(Edge {
+                        faces: Vec::new(),
+                        sharpness: 0.0,
+                        point,
+                    })
+                    .faces
+                    .push(f);
+            }
+        }
+        for crease in creases {
+            let [a, b] = crease.vertices;
+            if let Some(edge) = edges.get_mut(&edge(a, b)) {
+                edge.sharpness = edge.sharpness.max(crease.sharpness);
+            }
+        }
+        for edge in edges.values_mut() {
+            if edge.faces.len() != 2 {
+                edge.sharpness = f32::INFINITY;
+            }
+        }
+
+        let face_offset = vertex_count + edges.len();
+        let mut positions = vec![Vec3::zero(); face_offset + self.faces.len()];
+        positions[face_offset..].copy_from_slice(&face_points);
+
+        // Edge points, which move from the midpoint towards the faces on either side the less
+        // sharp the edge is
+        let mut incident = vec![Vec::new(); vertex_count];
+        for &(a, b) in &keys {
+            let edge = &edges[&(a, b)];
+            let (pa, pb) = (self.positions[a as usize], self.positions[b as usize]);
+            let midpoint = 0.5 * (pa + pb);
+            positions[edge.point as usize] = if edge.sharpness >= 1.0 {
+                midpoint
+            } else {
+                let faces = face_points[edge.faces[0]] + face_points[edge.faces[1]];
+                let smooth = 0.25 * (pa + pb + faces);
+                smooth.lerp(midpoint, edge.sharpness)
+            };
+
+            incident[a as usize].push((midpoint, edge.sharpness));
+            incident[b as usize].push((midpoint, edge.sharpness));
+        }
+
+        // Vertex points, smooth where fewer than two sharp edges meet, sliding along a crease
+        // between two and staying in place at corners where more meet
+        let mut vertex_faces = vec![Vec::new(); vertex_count];
+        for (f, face) in self.faces.iter().enumerate() {
+            for corner in face {
+                vertex_faces[corner.position as usize].push(f);
+            }
+        }
+        for v in 0..vertex_count {
+            let (p, edges, faces) = (self.positions[v], &incident[v], &vertex_faces[v]);
+            if faces.is_empty() {
+                positions[v] = p;
+                continue;
+            }
+
+            let n = edges.len() as f32;
+            let smooth = if edges.len() >= 3 {
+                let q = faces
+                    .iter()
+                    .map(|&f| face_points[f])
+                    .fold(Vec3::zero(), |a, b| a + b)
+                    / faces.len() as f32;
+                let r = edges.iter().map(|e| e.0).fold(Vec3::zero(), |a, b| a + b) / n;
+                (q + 2.0 * r + (n - 3.0) * p) / n
+            } else {
+                p
+            };
+
+            let sharp: Vec<_> = edges.iter().filter(|e| e.1 > 0.0).collect();
+            let target = match sharp.len() {
+                0 | 1 => None,
+                // A corner of the boundary, with a single face
+                2 if faces.len() == 1 => Some(p),
+                // Midpoints are halfway to the other ends, so this is (a + 6p + b) / 8
+                2 => Some(0.25 * (sharp[0].0 + sharp[1].0) + 0.5 * p),
+                _ => Some(p),
+            };
+            positions[v] = match target {
+                Some(target) => {
+                    let sharpness = sharp.iter().map(|e| e.1).sum::<f32>() / sharp.len() as f32;
+                    if sharpness >= 1.0 {
+                        target
+                    } else {
+                        smooth.lerp(target, sharpness)
+                    }
+                }
+                None => smooth,
+            };
+        }
+
+        // Every face becomes a quad for each of its corners
+        let mut uvs = self.uvs.clone();
+        let mut edge_uvs = HashMap::new();
+        let mut faces = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let k = face.len();
+            let face_uv = if face.iter().all(|corner| corner.uv.is_some()) {
+                let (u, v) = face
+                    .iter()
+                    .map(|corner| self.uvs[corner.uv.unwrap() as usize])
+                    .fold((0.0, 0.0), |(u, v), (a, b)| (u + a, v + b));
+                uvs.push((u / k as f32, v / k as f32));
+                Some(uvs.len() as u32 - 1)
+            } else {
+                None
+            };
+
+            // Texture coordinates of the edge points, shared with the face on the other side
+            // when it has the same ones
+            let mut mids = Vec::with_capacity(k);
+            for i in 0..k {
+                let mid = match (face[i].uv, face[(i + 1) % k].uv, face_uv) {
+                    (Some(a), Some(b), Some(_)) => {
+                        Some(*edge_uvs.entry(edge(a, b)).or_insert_with(|| {
+                            let ((ua, va), (ub, vb)) = (self.uvs[a as usize], self.uvs[b as usize]);
+                            uvs.push((0.5 * (ua + ub), 0.5 * (va + vb)));
+                            uvs.len() as u32 - 1
+                        }))
+                    }
+                    _ => None,
+                };
+                mids.push(mid);
+            }
+
+            let edge_point =
+                |i: usize| edges[&edge(face[i].position, face[(i + 1) % k].position)].point;
+            let face_point = (face_offset + f) as u32;
+            for i in 0..k {
+                let prev = (i + k - 1) % k;
+                let corner = |position, uv| Corner {
+                    position,
+                    uv: face_uv.and(uv),
+                    normal: None,
+                };
+                faces.push(vec![
+                    corner(face[i].position, face[i].uv),
+                    corner(edge_point(i), mids[i]),
+                    corner(face_point, face_uv),
+                    corner(edge_point(prev), mids[prev]),
+                ]);
+            }
+        }
+
+        // Both halves of a crease stay sharp for one level less
+        let creases = creases
+            .iter()
+            .filter(|crease| crease.sharpness > 1.0)
+            .flat_map(|crease| {
+                let [a, b] = crease.vertices;
+                let point = edges[&edge(a, b)].point;
+                let sharpness = crease.sharpness - 1.0;
+                vec![
+                    Crease {
+                        vertices: [a, point],
+                        sharpness,
+                    },
+                    Crease {
+                        vertices: [point, b],
+                        sharpness,
+                    },
+                ]
+            })
+            .collect();
+
+        let mesh = PolygonMesh {
+            positions,
+            uvs,
+            normals: Vec::new(),
+            faces,
+        };
+        (mesh, creases)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::vec3;
+
+    fn mesh(positions: &[Vec3], faces: &[&[u32]]) -> PolygonMesh {
+        PolygonMesh {
+            positions: positions.to_vec(),
+            uvs: Vec::new(),
+            normals: Vec::new(),
+            faces: faces
+                .iter()
+                .map(|face| {
+                    face.iter()
+                        .map(|&position| Corner {
+                            position,
+                            uv: None,
+                            normal: None,
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    fn cube() -> PolygonMesh {
+        mesh(
+            &[
+                vec3(-1.0, -1.0, -1.0),
+                vec3(1.0, -1.0, -1.0),
+                vec3(1.0, 1.0, -1.0),
+                vec3(-1.0, 1.0, -1.0),
+                vec3(-1.0, -1.0, 1.0),
+                vec3(1.0, -1.0, 1.0),
+                vec3(1.0, 1.0, 1.0),
+                vec3(-1.0, 1.0, 1.0),
+            ],
+            &[
+                &[0, 3, 2, 1],
+                &[4, 5, 6, 7],
+                &[0, 1, 5, 4],
+                &[3, 7, 6, 2],
+                &[0, 4, 7, 3],
+                &[1, 2, 6, 5],
+            ],
+        )
+    }
+
+    // Two quads folded into a valley along y = 0, open on every other side
+    fn valley() -> PolygonMesh {
+        mesh(
+            &[
+                vec3(0.0, -1.0, 1.0),
+                vec3(1.0, -1.0, 1.0),
+                vec3(2.0, -1.0, 1.0),
+                vec3(0.0, 0.0, 0.0),
+                vec3(1.0, 0.0, 0.0),
+                vec3(2.0, 0.0, 0.0),
+                vec3(0.0, 1.0, 1.0),
+                vec3(1.0, 1.0, 1.0),
+                vec3(2.0, 1.0, 1.0),
+            ],
+            &[&[0, 1, 4, 3], &[1, 2, 5, 4], &[3, 4, 7, 6], &[4, 5, 8, 7]],
+        )
+    }
+
+    fn sorted_abs(p: Vec3) -> [f32; 3] {
+        let mut coordinates = [p.x().abs(), p.y().abs(), p.z().abs()];
+        coordinates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        coordinates
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        for (a, b) in a.iter().zip(&b) {
+            assert!((a - b).abs() < 1e-6, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn cube_refines_into_quads_with_smooth_points() {
+        let refined = cube().subdivide(1, &[]).unwrap();
+
+        assert_eq!(refined.positions.len(), 8 + 12 + 6);
+        assert_eq!(refined.faces.len(), 24);
+        assert!(refined.faces.iter().all(|face| face.len() == 4));
+
+        // Vertices of valence three, (Q + 2R) / 3 with Q at a third and R at two thirds
+        let corner = 5.0 / 9.0;
+        for (refined, original) in refined.positions[..8].iter().zip(&cube().positions) {
+            assert!((*refined - corner * *original).length() < 1e-6);
+        }
+        // Edge points average the ends with the face points on either side
+        for &p in &refined.positions[8..20] {
+            assert_close(sorted_abs(p), [0.0, 0.75, 0.75]);
+        }
+        // Face points are the centers of the faces
+        for &p in &refined.positions[20..] {
+            assert_close(sorted_abs(p), [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn infinitely_sharp_crease_stays_on_its_line() {
+        let creases = [
+            Crease {
+                vertices: [3, 4],
+                sharpness: f32::INFINITY,
+            },
+            Crease {
+                vertices: [4, 5],
+                sharpness: f32::INFINITY,
+            },
+        ];
+        let refined = valley().subdivide(3, &creases).unwrap();
+
+        let on_line: Vec<_> = refined
+            .positions
+            .iter()
+            .filter(|p| p.y().abs() < 1e-6)
+            .collect();
+        assert_eq!(on_line.len(), 2 * 8 + 1);
+        assert!(on_line.iter().all(|p| p.z().abs() < 1e-6));
+        assert_eq!(refined.positions[4], vec3(1.0, 0.0, 0.0));
+
+        // Without the crease the bottom of the valley is rounded off
+        let smooth = valley().subdivide(3, &[]).unwrap();
+        assert!(smooth.positions[4].z() > 0.1);
+    }
+
+    #[test]
+    fn fractional_crease_lies_between_smooth_and_sharp() {
+        let crease = |sharpness| {
+            let creases = [
+                Crease {
+                    vertices: [3, 4],
+                    sharpness,
+                },
+                Crease {
+                    vertices: [4, 5],
+                    sharpness,
+                },
+            ];
+            valley().subdivide(1, &creases).unwrap().positions[4].z()
+        };
+
+        let (smooth, half, sharp) = (crease(0.0), crease(0.5), crease(1.0));
+        assert_eq!(sharp, 0.0);
+        assert!((half - 0.5 * smooth).abs() < 1e-6);
+    }
+
+    #[test]
+    fn boundary_corners_stay_in_place() {
+        let refined = valley().subdivide(2, &[]).unwrap();
+
+        for i in [0, 2, 6, 8] {
+            assert_eq!(refined.positions[i], valley().positions[i]);
+        }
+        // The rest of the boundary only slides along its straight sides
+        assert_eq!(refined.positions[1], vec3(1.0, -1.0, 1.0));
+        assert!(refined
+            .positions
+            .iter()
+            .all(|p| (0.0..=2.0).contains(&p.x()) && (-1.0..=1.0).contains(&p.y())));
+    }
+
+    #[test]
+    fn crease_must_be_an_edge() {
+        let creases = [Crease {
+            vertices: [0, 4],
+            sharpness: 1.0,
+        }];
+        let error = valley().subdivide(1, &creases).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Crease between vertices 1 and 5 is not an edge of the mesh"
+        );
+    }
+
+    #[test]
+    fn subdividing_past_the_budget_fails() {
+        assert!(valley().subdivide(3, &[]).is_ok());
+
+        let error = valley().subdivide(16, &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Subdividing the mesh 16 times would make more than {} triangles",
+                MAX_TRIANGLES
+            )
+        );
+        assert!(valley().subdivide(u32::MAX, &[]).is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use crate::material::Material;
+use crate::{
+    material::{orthonormal_basis, Material},
+    medium::Medium,
+};
 use glam::{vec3, Vec3};
 use std::sync::Arc;
 
@@ -8,6 +11,8 @@ pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
     pub inv_direction: Vec3,
+    /// Point in time the ray was sent, used for motion blur
+    pub time: f32,
 }
 
 impl Ray {
@@ -22,19 +27,106 @@ impl Ray {
             origin,
             direction,
             inv_direction,
+            time: 0.0,
         }
     }
 
+    pub fn at_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
     pub fn point_at_parameter(&self, t: f32) -> Vec3 {
         self.origin + t * self.direction
     }
 }
 
+/// Orthonormal frame around the normal a surface is shaded with
+#[derive(Clone, Copy, Debug)]
+pub struct ShadingFrame {
+    pub normal: Vec3,
+    /// Follows the direction u grows in on the surface, where it has texture coordinates
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+}
+
+impl ShadingFrame {
+    /// A frame around the normal with an arbitrary tangent
+    pub fn from_normal(normal: Vec3) -> Self {
+        let (tangent, bitangent) = orthonormal_basis(normal);
+
+        Self {
+            normal,
+            tangent,
+            bitangent,
+        }
+    }
+
+    /// A frame around the normal with the tangent as close to the one given as it can be
+    pub fn new(normal: Vec3, tangent: Vec3) -> Self {
+        let tangent = tangent - tangent.dot(normal) * normal;
+        if tangent.length_squared() < 1e-12 {
+            return Self::from_normal(normal);
+        }
+        let tangent = tangent.normalize();
+
+        Self {
+            normal,
+            tangent,
+            bitangent: normal.cross(tangent),
+        }
+    }
+
+    /// Turns a direction in the frame, with z along the normal, into world space
+    pub fn to_world(self, v: Vec3) -> Vec3 {
+        v.x() * self.tangent + v.y() * self.bitangent + v.z() * self.normal
+    }
+}
+
 /// Contains data to be used in the generation of a new ray as a result of an intersection.
 #[derive(Clone, Debug)]
 pub struct Hit {
     pub t: f32,
     pub point: Vec3,
+    /// Normal of the actual surface, which decides what side of it rays are on
     pub normal: Vec3,
+    /// The frame the surface is shaded in, which normal and bump maps bend away from the surface
+    pub shading: ShadingFrame,
+    /// Texture coordinates
+    pub uv: (f32, f32),
     pub material: Option<Arc<dyn Material>>,
+    /// Set when the surface is the boundary of a volume filled with this medium
+    pub medium: Option<Arc<dyn Medium>>,
+    /// Index of the scene instance that was hit, filled in by the BVH
+    pub instance: Option<u32>,
+}
+
+impl Hit {
+    /// A hit on a surface without texture coordinates, shaded with its own normal
+    pub fn new(t: f32, point: Vec3, normal: Vec3) -> Self {
+        Self {
+            t,
+            point,
+            normal,
+            shading: ShadingFrame::from_normal(normal),
+            uv: (0.0, 0.0),
+            material: None,
+            medium: None,
+            instance: None,
+        }
+    }
+
+    /// Sets the texture coordinates, and the direction u grows in on the surface
+    pub fn with_uv(mut self, uv: (f32, f32), tangent: Vec3) -> Self {
+        self.uv = uv;
+        self.shading = ShadingFrame::new(self.shading.normal, tangent);
+        self
+    }
+
+    /// Turns the surface around
+    pub fn flip(&mut self) {
+        self.normal = -self.normal;
+        self.shading.normal = -self.shading.normal;
+        self.shading.bitangent = -self.shading.bitangent;
+    }
 }
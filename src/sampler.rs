@@ -0,0 +1,385 @@
+use crate::DefaultRng;
+use rand::prelude::*;
+use serde::Deserialize;
+
+/// Generates the random numbers used to build a path, one dimension at a time.
+/// Every sample of every pixel gets its own stream, so samplers can spread the samples of a
+/// pixel evenly over each dimension instead of drawing independent white noise.
+pub trait Sampler {
+    /// Starts the stream for sample number index of pixel
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32);
+
+    /// The next dimension of the current sample, in [0, 1)
+    fn next_1d(&mut self) -> f32;
+
+    /// The next two dimensions of the current sample, in [0, 1)^2
+    fn next_2d(&mut self) -> (f32, f32);
+}
+
+/// The kinds of samplers that can be picked in the settings
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl Default for SamplerKind {
+    fn default() -> Self {
+        SamplerKind::Independent
+    }
+}
+
+impl SamplerKind {
+    /// Creates a sampler of this kind, seed picks the random numbers
+    pub fn sampler(self, seed: u64) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+/// Uniform random numbers without any structure.
+/// The generator is reseeded for every sample, so the numbers only depend on the seed, pixel and
+/// sample index and not on which thread happens to render the pixel.
+pub struct IndependentSampler {
+    seed: u64,
+    rng: DefaultRng,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: DefaultRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        let seed = hash(&[self.seed, pixel.0 as u64, pixel.1 as u64, index as u64]);
+        self.rng = DefaultRng::seed_from_u64(seed);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.rng.gen(), self.rng.gen())
+    }
+}
+
+/// Where in the sequence a sampler is, shared by the samplers that are pure functions of it
+#[derive(Clone, Copy, Debug, Default)]
+struct SampleState {
+    seed: u64,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+}
+
+impl SampleState {
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    /// Hash of the pixel and current dimension, and moves on to the next dimension
+    fn next_dimension(&mut self) -> u64 {
+        let hash = hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            self.dimension as u64,
+        ]);
+        self.dimension += 1;
+
+        hash
+    }
+}
+
+/// Strata along each side of the grid two dimensions are stratified in together
+const STRATA_PER_SIDE: u32 = 4;
+
+/// Strata each dimension is split into per round of samples
+const STRATA: u32 = STRATA_PER_SIDE * STRATA_PER_SIDE;
+
+/// Jittered stratified samples, each dimension is split into a fixed number of strata and the
+/// strata are visited in a different random order for each pixel and dimension.
+/// Pairs of dimensions are stratified together in a grid. The strata don't depend on how many
+/// samples are taken, so a render resumed with more samples continues the same pattern.
+pub struct StratifiedSampler {
+    state: SampleState,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: SampleState {
+                seed,
+                ..Default::default()
+            },
+        }
+    }
+
+    // The stratum of the current sample out of count strata, drawing more samples than
+    // there are strata starts a new random round through them
+    fn stratum(&self, count: u32, hash: u64) -> u32 {
+        let index = self.state.index;
+        let round = (index / count) as u64;
+        permutation_element(index % count, count, mix(hash ^ round) as u32)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let hash = self.state.next_dimension();
+        let stratum = self.stratum(STRATA, hash);
+        let jitter = to_float(mix(hash ^ self.state.index as u64 ^ 0x5bd1_e995) as u32);
+
+        (stratum as f32 + jitter) / STRATA as f32
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let hash = self.state.next_dimension();
+        let n = STRATA_PER_SIDE;
+        let stratum = self.stratum(n * n, hash);
+        let jitter = mix(hash ^ self.state.index as u64 ^ 0x5bd1_e995);
+
+        (
+            ((stratum % n) as f32 + to_float(jitter as u32)) / n as f32,
+            ((stratum / n) as f32 + to_float((jitter >> 32) as u32)) / n as f32,
+        )
+    }
+}
+
+/// The Halton sequence with a prime base per dimension. Every digit is scrambled by a random
+/// affine permutation chosen per pixel and dimension, which keeps the stratification intact.
+/// Dimensions beyond the table of primes fall back to hashed random numbers.
+pub struct HaltonSampler {
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: SampleState {
+                seed,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.state.dimension as usize;
+        let hash = self.state.next_dimension();
+
+        match PRIMES.get(dimension) {
+            Some(&base) => scrambled_radical_inverse(self.state.index, base, hash),
+            None => to_float(mix(hash ^ self.state.index as u64) as u32),
+        }
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+/// The first two dimensions of the Sobol sequence, padded into as many dimensions as needed by
+/// shuffling the order of the points and Owen scrambling them per pixel and pair of dimensions.
+/// Based on "Practical Hash-based Owen Scrambling" by Brent Burley.
+pub struct SobolSampler {
+    state: SampleState,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: SampleState {
+                seed,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.next_2d().0
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let hash = self.state.next_dimension();
+        let index = nested_uniform_scramble(self.state.index, hash as u32);
+        let x = nested_uniform_scramble(sobol_0(index), mix(hash ^ 1) as u32);
+        let y = nested_uniform_scramble(sobol_1(index), mix(hash ^ 2) as u32);
+
+        (to_float(x), to_float(y))
+    }
+}
+
+/// The first 64 primes, used as the bases of the Halton sequence
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// Mirrors the digits of index in base around the decimal point, permuting each digit
+fn scrambled_radical_inverse(mut index: u32, base: u32, hash: u64) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_n = 1.0;
+    let mut result = 0.0;
+    let mut digit_index = 0u64;
+
+    // Enough digits to fill an f32 even when the index runs out of them
+    while inv_base_n > 1e-8 {
+        let digit_hash = mix(hash ^ digit_index);
+        let scale = 1 + (digit_hash % (base as u64 - 1)) as u32;
+        let shift = ((digit_hash >> 32) % base as u64) as u32;
+
+        let digit = index % base;
+        let digit = (scale as u64 * digit as u64 + shift as u64) % base as u64;
+
+        inv_base_n *= inv_base;
+        result += digit as f64 * inv_base_n;
+        index /= base;
+        digit_index += 1;
+    }
+
+    (result as f32).min(ONE_MINUS_EPSILON)
+}
+
+// The first dimension of the Sobol sequence is the van der Corput sequence
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+fn sobol_1(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut direction = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+
+    result
+}
+
+// Hash based permutation that only lets bits affect more significant bits
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+// Owen scrambling of a 32-bit fixed point number in [0, 1)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Element i of a random permutation of 0..l picked by p, from "Correlated Multi-Jittered
+/// Sampling" by Andrew Kensler
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < l {
+            break;
+        }
+    }
+
+    i.wrapping_add(p) % l
+}
+
+/// The largest f32 below one
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+// Maps 32 random bits to [0, 1)
+fn to_float(x: u32) -> f32 {
+    (x >> 8) as f32 / (1u32 << 24) as f32
+}
+
+/// The splitmix64 finalizer
+pub fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    x
+}
+
+/// Hashes a list of values into a single one
+pub fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0x9e37_79b9_7f4a_7c15, |h, &v| mix(h ^ mix(v)))
+}
+
+/// Hashes a string of bytes into a single value
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let words: Vec<_> = bytes
+        .chunks(8)
+        .map(|chunk| {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u64::from_le_bytes(word)
+        })
+        .collect();
+
+    hash(&[hash(&words), bytes.len() as u64])
+}
@@ -1,19 +1,36 @@
 use crate::{
-    bvh::BVH, camera::Camera, color, material::Material, primitives::Instance, DefaultRng,
-    SettingsConfig,
+    aov::SurfaceSample,
+    bvh::Bvh,
+    camera::Camera,
+    color,
+    film::Film,
+    filter::FilterSampler,
+    material::Material,
+    medium::{Fog, Medium},
+    primitives::Instance,
+    ray::Hit,
+    sampler::hash_bytes,
+    CameraConfig, PathState, Radiance, SettingsConfig,
 };
-use glam::{vec3, Vec3};
+use glam::Vec3;
 use image::{save_buffer, ColorType};
-use itertools::iproduct;
-use rand::prelude::*;
 use rayon::prelude::*;
-use smallvec::*;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 /// Traced image
 pub struct Image {
     pub dimensions: (u32, u32),
     pub buffer: Vec<u8>,
+    /// Whether every pixel has an alpha value after its color
+    pub alpha: bool,
 }
 
 impl Image {
@@ -28,6 +45,42 @@ impl Image {
         Self {
             dimensions: (width, height),
             buffer,
+            alpha: false,
+        }
+    }
+
+    /// Gamma corrects and quantizes linear colors, given row by row from the top
+    pub fn from_colors(colors: &[Vec3], width: u32, height: u32, gamma: f32) -> Self {
+        let buffer = colors
+            .iter()
+            .flat_map(|&pixel| quantize(pixel, gamma))
+            .collect();
+
+        Self::from(buffer, width, height)
+    }
+
+    /// Like from_colors, with an alpha value for every pixel. The colors are premultiplied by
+    /// alpha, which is divided out as PNG expects.
+    pub fn from_colors_with_alpha(
+        colors: &[Vec3],
+        alphas: &[f32],
+        width: u32,
+        height: u32,
+        gamma: f32,
+    ) -> Self {
+        let buffer = colors
+            .iter()
+            .zip(alphas)
+            .flat_map(|(&pixel, &alpha)| {
+                let pixel = if alpha > 0.0 { pixel / alpha } else { pixel };
+                let [r, g, b] = quantize(pixel, gamma);
+                [r, g, b, (254.99 * alpha) as u8]
+            })
+            .collect();
+
+        Self {
+            alpha: true,
+            ..Self::from(buffer, width, height)
         }
     }
 
@@ -37,15 +90,38 @@ impl Image {
             &self.buffer,
             self.dimensions.0,
             self.dimensions.1,
-            ColorType::Rgb8,
+            if self.alpha {
+                ColorType::Rgba8
+            } else {
+                ColorType::Rgb8
+            },
         )?;
 
         Ok(())
     }
 }
 
+// Gamma corrects a linear color and converts it from [0, 1] to [0, 255]
+fn quantize(pixel: Vec3, gamma: f32) -> [u8; 3] {
+    // Negative filter lobes can leave pixels slightly below zero
+    let pixel = pixel.max(Vec3::zero());
+
+    // Gamma correct
+    let pixel = Vec3::new(
+        pixel.x().powf(1.0 / gamma),
+        pixel.y().powf(1.0 / gamma),
+        pixel.z().powf(1.0 / gamma),
+    );
+
+    // Convert from [0, 1] to [0, 255]
+    let pixel = 254.99 * pixel;
+
+    [pixel.x() as u8, pixel.y() as u8, pixel.z() as u8]
+}
+
 /// A material cache that stores all the materials in the scene
 pub struct Materials {
+    #[allow(dead_code)]
     inner: HashMap<String, Arc<dyn Material + Send + Sync>>,
 }
 
@@ -57,108 +133,175 @@ impl Materials {
     }
 }
 
+/// Where the film is saved to resume rendering later
+const CHECKPOINT_PATH: &str = "checkpoint.bin";
+
 /// A Scene containing traceable objects and their materials.
 pub struct Scene {
     settings: SettingsConfig,
-    camera: Camera,
-    bvh: BVH,
+    camera: Box<dyn Camera>,
+    bvh: Bvh,
+    // Instances hold on to their own materials, the cache is not looked up yet
+    #[allow(dead_code)]
     materials: Materials,
+    fog: Option<Fog>,
+    filter: FilterSampler,
+    /// Set to end the render after the current pass
+    stop: Arc<AtomicBool>,
+    /// Where the film is checkpointed to, every frame of an animation has its own
+    checkpoint_path: String,
+    /// Identifies the contents of the scene in checkpoints
+    scene_hash: u64,
+    /// Numbers the materials in the order they first appear in the scene, by address
+    material_ids: HashMap<usize, u32>,
 }
 
 impl Scene {
-    pub fn new(settings: SettingsConfig, primitives: Vec<Instance>) -> Self {
-        let camera = Camera::new(
-            vec3(13.0, 2.0, 3.0),
-            vec3(4.0, 1.0, 0.0),
-            vec3(0.0, 1.0, 0.0),
-            20.0,
-            settings.width() as f32 / settings.height() as f32,
-            0.1,
+    pub fn new(settings: SettingsConfig, primitives: Vec<Instance>) -> anyhow::Result<Self> {
+        // A seed picked at random would never match the one the checkpoint was rendered with
+        let resume = settings
+            .checkpoint
+            .map_or(false, |checkpoint| checkpoint.resume);
+        anyhow::ensure!(
+            !resume || settings.seed.is_some(),
+            "Resuming from a checkpoint needs the seed it was rendered with in the settings"
         );
-        let bvh = BVH::new(primitives);
+
+        let camera = settings.camera.camera(
+            settings.width() as f32 / settings.height() as f32,
+            settings.shutter,
+        )?;
+        let fingerprints: String = primitives.iter().map(Instance::fingerprint).collect();
+        let scene_hash = hash_bytes(fingerprints.as_bytes());
+        let mut material_ids = HashMap::new();
+        for material in primitives.iter().filter_map(Instance::material) {
+            let next = material_ids.len() as u32;
+            material_ids
+                .entry(material_address(material))
+                .or_insert(next);
+        }
+        let bvh = Bvh::new(primitives);
         let materials = Materials::new();
+        let fog = settings.fog.map(|fog| fog.fog());
+        let filter = settings.filter.sampler();
 
-        Scene {
+        Ok(Scene {
             settings,
             camera,
             bvh,
             materials,
-        }
+            fog,
+            filter,
+            stop: Arc::new(AtomicBool::new(false)),
+            checkpoint_path: CHECKPOINT_PATH.to_string(),
+            scene_hash,
+            material_ids,
+        })
     }
 
-    pub fn trace(&self) -> Image {
-        let start = std::time::Instant::now();
-
-        // Cartesian product
-        let pixels: Vec<_> =
-            iproduct!(0..self.settings.width(), 0..self.settings.height()).collect();
-
-        // Main pathtracing
-        let (global_ray_count, mut pixels): (Vec<u32>, Vec<_>) = pixels
-            .into_par_iter()
-            .map_with(DefaultRng::from_entropy(), |mut rng, (x, y)| {
-                let mut pixel = Vec3::zero();
-                let mut ray_count = 0;
-
-                // Anti-aliasing via multi-sampling
-                for _ in 0..self.settings.samples {
-                    let u = (rng.gen::<f32>() + x as f32) / self.settings.width() as f32;
-                    let v = (rng.gen::<f32>() + y as f32) / self.settings.height() as f32;
-
-                    let ray = self.camera.ray(u, v, &mut rng);
-
-                    let mut instance_ray_count = 0;
-                    pixel += color(
-                        ray,
-                        &mut instance_ray_count,
-                        &self.bvh,
-                        &mut rng,
-                        self.settings.max_bounces,
-                    );
-                    ray_count += instance_ray_count;
-                }
+    /// Moves on to a frame of an animation, seen through the camera given and checkpointed next
+    /// to the frame image. Only the camera moves, so the BVH is kept for every frame.
+    pub fn set_frame(&mut self, name: &str, camera: CameraConfig) -> anyhow::Result<()> {
+        self.camera = camera.camera(
+            self.settings.width() as f32 / self.settings.height() as f32,
+            self.settings.shutter,
+        )?;
+        self.settings.camera = camera;
+        self.checkpoint_path = format!("{}.checkpoint.bin", name);
+
+        Ok(())
+    }
+
+    /// A flag that stops the render after the current pass when set, keeping the samples so far
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
 
-                // Normalize over samples
-                pixel /= self.settings.samples as f32;
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
 
-                // Gamma correct
-                pixel = Vec3::new(
-                    pixel.x().powf(1.0 / self.settings.gamma),
-                    pixel.y().powf(1.0 / self.settings.gamma),
-                    pixel.z().powf(1.0 / self.settings.gamma),
+    /// The film to render into, resumed from the last checkpoint if the settings ask for it and
+    /// limited to the render region
+    pub fn film(&self) -> anyhow::Result<Film> {
+        match self.settings.checkpoint {
+            Some(checkpoint) if checkpoint.resume && Path::new(&self.checkpoint_path).exists() => {
+                let film = Film::load_checkpoint(
+                    &self.checkpoint_path,
+                    self.settings.checkpoint_hash(),
+                    self.scene_hash,
+                )?;
+                println!(
+                    "Resuming from {} after {} passes",
+                    self.checkpoint_path, film.passes
                 );
 
-                // Convert from [0, 1] to [0, 255]
-                let pixel = 254.99 * pixel;
+                Ok(film)
+            }
+            _ => Ok(Film::new(self.settings.width(), self.settings.height())),
+        }
+        .and_then(|mut film| {
+            if self.settings.aovs {
+                film.enable_aovs();
+            }
+            if let Some(region) = self.settings.region {
+                film.set_region(region.region(film.width(), film.height())?);
+            }
+            Ok(film)
+        })
+    }
 
-                (ray_count, ((x, y), pixel))
-            })
-            .unzip();
+    pub fn trace(&self, film: &mut Film) {
+        let start = Instant::now();
+        let mut last_preview = start;
+        let mut last_checkpoint = start;
+        let mut global_ray_count = 0;
 
-        // Add up all the ray counts
-        let global_ray_count: u32 = global_ray_count.into_iter().sum();
+        // Progressive renders take a single sample per pixel at a time
+        let pass_samples = if self.settings.progressive.is_some() {
+            1
+        } else {
+            self.settings.samples
+        };
+        while film.activate_below(self.settings.samples) > 0 && !self.stopped() {
+            global_ray_count += self.trace_pass(film, pass_samples, self.settings.samples);
+            self.preview(film, &mut last_preview);
+            self.checkpoint(film, &mut last_checkpoint, false);
+        }
 
-        // Sort the pixels
-        pixels.sort_unstable_by(|((x1, y1), _), ((x2, y2), _)| {
-            let a = (self.settings.height() - y1) * self.settings.width() + x1;
-            let b = (self.settings.height() - y2) * self.settings.width() + x2;
+        // Keep refining the pixels with the largest error until they converge or the budget runs out
+        if let Some(adaptive) = self.settings.adaptive {
+            loop {
+                let active = film.update_active(adaptive.threshold, adaptive.max_samples);
+                let out_of_time = adaptive
+                    .time_budget
+                    .map_or(false, |budget| start.elapsed().as_secs_f32() >= budget);
+                let out_of_samples = adaptive
+                    .sample_budget
+                    .map_or(false, |budget| film.samples() >= budget);
+                if active == 0 || out_of_time || out_of_samples || self.stopped() {
+                    break;
+                }
 
-            Ord::cmp(&a, &b)
-        });
+                global_ray_count +=
+                    self.trace_pass(film, adaptive.pass_samples, adaptive.max_samples);
+                self.preview(film, &mut last_preview);
+                self.checkpoint(film, &mut last_checkpoint, false);
+            }
+        }
 
-        // Reinterpret the pixels into expected image format
-        let pixels: Vec<_> = pixels
-            .into_iter()
-            .flat_map(|(_, pixel)| {
-                let p: SmallVec<[u8; 3]> =
-                    smallvec![pixel.x() as u8, pixel.y() as u8, pixel.z() as u8];
-                p
-            })
-            .collect();
+        // Always leave a checkpoint behind so more samples can be added later
+        self.checkpoint(film, &mut last_checkpoint, true);
 
-        let image = Image::from(pixels, self.settings.width(), self.settings.height());
+        let region = film.region();
+        let pixel_count = (region.width * region.height) as f64;
+        println!(
+            "Passes: {}\nAverage samples per pixel: {:.2}",
+            film.passes,
+            film.samples() as f64 / pixel_count
+        );
 
-        let finished = std::time::Instant::now();
+        let finished = Instant::now();
         let duration = finished.duration_since(start);
 
         let global_ray_count = global_ray_count / 1_000_000;
@@ -169,15 +312,189 @@ impl Scene {
             duration, global_ray_count, rays_per_second
         );
 
-        let min_estimated_total_rays =
-            self.settings.width() * self.settings.height() * self.settings.samples;
-        let max_estimated_total_rays = min_estimated_total_rays * self.settings.max_bounces;
+        let min_estimated_total_rays = film.samples();
+        let max_estimated_total_rays = min_estimated_total_rays * self.settings.max_bounces as u64;
         println!(
             "Minimum estimated total rays: {:.2}M\nMaximum estimated total rays: {:.2}M",
             min_estimated_total_rays / 1_000_000,
             max_estimated_total_rays / 1_000_000
         );
+    }
+
+    /// Writes the film to preview.png if a progressive render is due for a new preview
+    fn preview(&self, film: &Film, last_preview: &mut Instant) {
+        let progressive = match self.settings.progressive {
+            Some(progressive) => progressive,
+            None => return,
+        };
+
+        let time_due = progressive.interval.map_or(false, |interval| {
+            last_preview.elapsed().as_secs_f32() >= interval
+        });
+        let passes_due = progressive
+            .passes
+            .map_or(false, |every| every > 0 && film.passes % every == 0);
+        if !time_due && !passes_due {
+            return;
+        }
+
+        *last_preview = Instant::now();
+        let pixel_count = (film.width() * film.height()) as f64;
+        println!(
+            "Pass {}: {:.2} samples per pixel",
+            film.passes,
+            film.samples() as f64 / pixel_count
+        );
+        if let Err(error) = film.image(self.settings.gamma).save("preview.png") {
+            eprintln!("Failed to save preview image: {}", error);
+        }
+    }
+
+    /// Saves the film to the checkpoint file if one is due, or right away if forced
+    fn checkpoint(&self, film: &Film, last_checkpoint: &mut Instant, force: bool) {
+        let checkpoint = match self.settings.checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => return,
+        };
+
+        let time_due = checkpoint.interval.map_or(false, |interval| {
+            last_checkpoint.elapsed().as_secs_f32() >= interval
+        });
+        let passes_due = checkpoint
+            .passes
+            .map_or(false, |every| every > 0 && film.passes % every == 0);
+        if !force && !time_due && !passes_due {
+            return;
+        }
+
+        *last_checkpoint = Instant::now();
+        if let Err(error) = film.save_checkpoint(
+            &self.checkpoint_path,
+            self.settings.checkpoint_hash(),
+            self.scene_hash,
+        ) {
+            eprintln!("Failed to save checkpoint: {}", error);
+        }
+    }
+
+    /// What the auxiliary passes record about the first surface a camera ray reached
+    fn surface(&self, hit: &Hit) -> SurfaceSample {
+        let material = hit.material.as_ref();
+        SurfaceSample {
+            albedo: material.map_or(Vec3::zero(), |material| material.albedo(hit)),
+            normal: material.map_or(hit.normal, |material| material.shading(hit).normal),
+            position: hit.point,
+            depth: self.camera.depth(hit.point),
+            instance: hit.instance,
+            material: material
+                .and_then(|material| self.material_ids.get(&material_address(material)))
+                .copied(),
+        }
+    }
+
+    /// Adds samples more samples to every active pixel of the film, without going past limit
+    /// samples in any pixel. Returns the number of rays traced.
+    fn trace_pass(&self, film: &mut Film, samples: u32, limit: u32) -> u64 {
+        let width = film.width();
+        let height = film.height();
+        film.passes += 1;
+        let pass = film.passes;
+
+        let tiles = film.tiles_mut(self.settings.tile_size, self.settings.tile_order);
+        let tile_count = tiles.len();
+        let finished_tiles = AtomicUsize::new(0);
+        let (columns, rows) = self.camera.grid();
+
+        // Tiles are handed out in order as threads become free
+        let ray_count = tiles
+            .into_iter()
+            .par_bridge()
+            .map_init(
+                || self.settings.sampler.sampler(self.settings.seed()),
+                |sampler, tile| {
+                    let mut ray_count = 0;
+                    for ((x, row), pixel, mut aov) in
+                        tile.into_pixels().filter(|(_, pixel, _)| pixel.active)
+                    {
+                        // Film rows go from the top of the image down
+                        let y = height - 1 - row;
+
+                        // Anti-aliasing via multi-sampling
+                        for _ in 0..samples.min(limit.saturating_sub(pixel.samples)) {
+                            sampler.start_sample((x, y), pixel.samples);
+                            let ((dx, dy), weight) = self.filter.sample(sampler.next_2d());
+                            let u =
+                                within_cell(x as f32 + 0.5 + dx, x, width, columns) / width as f32;
+                            let v =
+                                within_cell(y as f32 + 0.5 + dy, y, height, rows) / height as f32;
+
+                            let ray = self.camera.weighted_ray(u, v, sampler.as_mut());
 
-        image
+                            // Some cameras leave parts of the image black, like the corners of a fisheye
+                            let mut path = PathState::new(self.settings.transparent_background);
+                            let fog = self.fog.as_ref().map(|fog| fog as &dyn Medium);
+                            let radiance = match ray {
+                                Some((ray, exposure)) => {
+                                    Vec3::splat(weight * exposure)
+                                        * color(
+                                            ray,
+                                            fog,
+                                            &mut path,
+                                            &self.bvh,
+                                            fog,
+                                            sampler.as_mut(),
+                                            self.settings.max_bounces,
+                                        )
+                                }
+                                None => {
+                                    path.alpha = 0.0;
+                                    Radiance::default()
+                                }
+                            };
+                            pixel.add_sample(radiance.total(), weight * path.alpha);
+                            ray_count += path.bounces as u64;
+
+                            if let Some(aov) = aov.as_mut() {
+                                let surface = path.first_hit.as_ref().map(|hit| self.surface(hit));
+                                aov.add_sample(surface, radiance.direct, radiance.indirect);
+                            }
+                        }
+                    }
+
+                    let finished = finished_tiles.fetch_add(1, Ordering::Relaxed) + 1;
+                    eprint!("\rPass {}: {}/{} tiles", pass, finished, tile_count);
+
+                    ray_count
+                },
+            )
+            .sum();
+        eprint!("\r\x1b[K");
+
+        ray_count
+    }
+}
+
+// Materials are told apart by the address they are stored at
+/// Mirrors a filter sample that strays over a seam between the images a camera lays out back
+/// into the cell of its pixel, along one axis of the film measured in pixels.
+/// The outer edges of the film are left alone.
+fn within_cell(position: f32, pixel: u32, size: u32, cells: u32) -> f32 {
+    // Cells split the film evenly, their seams need not fall between pixels
+    let cell_size = size as f32 / cells as f32;
+    let cell = (((pixel as f32 + 0.5) / cell_size) as u32).min(cells - 1);
+    let start = cell as f32 * cell_size;
+    let end = start + cell_size;
+
+    // Cameras pick the next cell from the seam on, so stay just short of it
+    if cell > 0 && position < start {
+        (2.0 * start - position).min(end - 1e-3)
+    } else if cell + 1 < cells && position >= end {
+        (2.0 * end - position).clamp(start, end - 1e-3)
+    } else {
+        position
     }
 }
+
+fn material_address(material: &Arc<dyn Material>) -> usize {
+    Arc::as_ptr(material) as *const () as usize
+}
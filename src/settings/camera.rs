@@ -0,0 +1,366 @@
+use crate::camera::*;
+use anyhow::bail;
+use glam::Vec3;
+use serde::Deserialize;
+
+/// Specifies where the camera is and how it projects the scene onto the image
+#[derive(Deserialize, Debug, Clone)]
+pub struct CameraConfig {
+    #[serde(default)]
+    projection: Projection,
+    origin: [f32; 3],
+    /// The point the camera looks at, and focuses on unless focus_distance is given
+    target: [f32; 3],
+    #[serde(default = "default_up")]
+    up: [f32; 3],
+    /// Vertical field of view in degrees, for perspective cameras
+    #[serde(default = "default_vfov")]
+    vfov: f32,
+    /// Diameter of the lens, larger gives more depth of field blur for perspective cameras
+    #[serde(default)]
+    aperture: f32,
+    /// Distance to the plane in focus, the distance to target if missing
+    focus_distance: Option<f32>,
+    /// Focal length in millimeters, replaces vfov, along with aperture when f_stop is given
+    focal_length: Option<f32>,
+    /// Focal length over the diameter of the aperture, with scene units in meters. Narrows the
+    /// aperture stop of realistic cameras.
+    f_stop: Option<f32>,
+    /// Height of the sensor in millimeters, used with focal_length
+    #[serde(default = "default_sensor_height")]
+    sensor_height: f32,
+    /// Number of diaphragm blades shaping the aperture into a polygon, round if less than 3
+    #[serde(default)]
+    aperture_blades: u32,
+    /// Rotation of the aperture polygon in degrees
+    #[serde(default)]
+    aperture_rotation: f32,
+    /// Grayscale image of the aperture for shaped bokeh, replaces the blades
+    aperture_image: Option<String>,
+    /// Width of the visible area in scene units, for orthographic cameras
+    #[serde(default = "default_view_width")]
+    view_width: f32,
+    /// Field of view in degrees across the image circle, for fisheye cameras
+    #[serde(default = "default_fisheye_fov")]
+    fov: f32,
+    /// How angles map onto the image, for fisheye cameras
+    #[serde(default)]
+    fisheye: FisheyeMapping,
+    /// Lens prescription for realistic cameras
+    lens_file: Option<String>,
+    /// Diagonal of the film behind the lenses of realistic cameras, in millimeters
+    #[serde(default = "default_film_diagonal")]
+    film_diagonal: f32,
+    /// Renders a left and a right eye onto the same image when given
+    stereo: Option<StereoConfig>,
+}
+
+/// Specifies the distance between two eyes and how they share the image
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct StereoConfig {
+    /// Distance between the eyes, in scene units
+    #[serde(default = "default_interocular")]
+    interocular: f32,
+    /// Distance at which the images of the two eyes line up, infinitely far if missing
+    convergence: Option<f32>,
+    #[serde(default)]
+    layout: StereoLayout,
+}
+
+fn default_film_diagonal() -> f32 {
+    35.0
+}
+
+fn default_interocular() -> f32 {
+    0.065
+}
+
+/// The ways a camera can map the scene onto the image
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    Perspective,
+    Orthographic,
+    /// The full sphere of directions as longitude and latitude
+    Equirectangular,
+    Fisheye,
+    /// Six 90 degree faces along the world axes, ignoring target and up
+    CubeMap,
+    /// Traced through the lenses of a lens file
+    Realistic,
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::Perspective
+    }
+}
+
+fn default_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_vfov() -> f32 {
+    20.0
+}
+
+fn default_sensor_height() -> f32 {
+    24.0
+}
+
+fn default_view_width() -> f32 {
+    10.0
+}
+
+fn default_fisheye_fov() -> f32 {
+    180.0
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            projection: Projection::Perspective,
+            origin: [13.0, 2.0, 3.0],
+            target: [4.0, 1.0, 0.0],
+            up: default_up(),
+            vfov: default_vfov(),
+            aperture: 0.1,
+            focus_distance: None,
+            focal_length: None,
+            f_stop: None,
+            sensor_height: default_sensor_height(),
+            aperture_blades: 0,
+            aperture_rotation: 0.0,
+            aperture_image: None,
+            view_width: default_view_width(),
+            fov: default_fisheye_fov(),
+            fisheye: FisheyeMapping::default(),
+            lens_file: None,
+            film_diagonal: default_film_diagonal(),
+            stereo: None,
+        }
+    }
+}
+
+impl CameraConfig {
+    /// Builds the camera for an image with the aspect ratio width / height, with its shutter
+    /// open over the interval of time given
+    pub fn camera(
+        &self,
+        aspect: f32,
+        shutter: Option<[f32; 2]>,
+    ) -> anyhow::Result<Box<dyn Camera>> {
+        let origin = Vec3::from(self.origin);
+        let target = Vec3::from(self.target);
+        let up = Vec3::from(self.up);
+        let [open, close] = shutter.unwrap_or([0.0, 0.0]);
+
+        let stereo = match self.stereo {
+            Some(stereo) => stereo,
+            None => return self.eye(origin, target, aspect, 0.0, shutter),
+        };
+
+        let eye_aspect = StereoCamera::eye_aspect(aspect, stereo.layout);
+        let (right, _, _) = look_at(origin, target, up);
+        let offset = stereo.interocular / 2.0;
+        let eye = |offset: f32| -> anyhow::Result<Box<dyn Camera>> {
+            if self.projection == Projection::Equirectangular {
+                return Ok(Box::new(
+                    OdsCamera::new(origin, target, up, offset, stereo.convergence)
+                        .with_shutter(open, close),
+                ));
+            }
+
+            // The eyes look in parallel, and shift their images in until they line up at the
+            // convergence distance, which keeps the image planes parallel too
+            let shift = match stereo.convergence {
+                Some(_) if self.projection != Projection::Perspective => {
+                    bail!("Stereo convergence needs a perspective or equirectangular camera")
+                }
+                Some(distance) => -offset / distance,
+                None => 0.0,
+            };
+            self.eye(
+                origin + offset * right,
+                target + offset * right,
+                eye_aspect,
+                shift,
+                shutter,
+            )
+        };
+
+        Ok(Box::new(StereoCamera::new(
+            eye(-offset)?,
+            eye(offset)?,
+            stereo.layout,
+        )))
+    }
+
+    // A single camera at origin looking at target, with perspective images shifted to the right
+    // by shift
+    fn eye(
+        &self,
+        origin: Vec3,
+        target: Vec3,
+        aspect: f32,
+        shift: f32,
+        shutter: Option<[f32; 2]>,
+    ) -> anyhow::Result<Box<dyn Camera>> {
+        let up = Vec3::from(self.up);
+        let [open, close] = shutter.unwrap_or([0.0, 0.0]);
+
+        let camera: Box<dyn Camera> = match self.projection {
+            Projection::Perspective => {
+                let vfov = match self.focal_length {
+                    Some(focal_length) => {
+                        2.0 * (self.sensor_height / (2.0 * focal_length))
+                            .atan()
+                            .to_degrees()
+                    }
+                    None => self.vfov,
+                };
+                let diameter = match (self.focal_length, self.f_stop) {
+                    (Some(focal_length), Some(f_stop)) => focal_length / 1000.0 / f_stop,
+                    _ => self.aperture,
+                };
+                let focus_distance = self
+                    .focus_distance
+                    .unwrap_or_else(|| (origin - target).length());
+                let aperture = match &self.aperture_image {
+                    Some(path) => Aperture::Image(ApertureImage::load(path)?),
+                    None if self.aperture_blades >= 3 => Aperture::Polygon {
+                        blades: self.aperture_blades,
+                        rotation: self.aperture_rotation.to_radians(),
+                    },
+                    None => Aperture::Circle,
+                };
+
+                Box::new(
+                    PerspectiveCamera::new(origin, target, up, vfov, aspect)
+                        .with_lens(diameter, focus_distance, aperture)
+                        .with_shift(shift)
+                        .with_shutter(open, close),
+                )
+            }
+            Projection::Orthographic => Box::new(
+                OrthographicCamera::new(origin, target, up, self.view_width, aspect)
+                    .with_shutter(open, close),
+            ),
+            Projection::Equirectangular => {
+                Box::new(EquirectangularCamera::new(origin, target, up).with_shutter(open, close))
+            }
+            Projection::Fisheye => Box::new(
+                FisheyeCamera::new(origin, target, up, self.fisheye, self.fov, aspect)
+                    .with_shutter(open, close),
+            ),
+            Projection::CubeMap => Box::new(CubeMapCamera::new(origin).with_shutter(open, close)),
+            Projection::Realistic => {
+                let path = self
+                    .lens_file
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Realistic cameras need a lens_file"))?;
+                let lenses = RealisticCamera::load_lenses(path)?;
+                let focus_distance = self
+                    .focus_distance
+                    .unwrap_or_else(|| (origin - target).length());
+
+                Box::new(
+                    RealisticCamera::new(
+                        origin,
+                        target,
+                        up,
+                        &lenses,
+                        self.film_diagonal,
+                        aspect,
+                        focus_distance,
+                        self.f_stop,
+                    )?
+                    .with_shutter(open, close),
+                )
+            }
+        };
+
+        Ok(camera)
+    }
+}
+
+/// Specifies how the camera moves over a sequence of frames
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AnimationConfig {
+    /// First and last frame to render
+    pub frames: Option<[u32; 2]>,
+    /// Values of the camera at given frames, linearly interpolated in between and held before
+    /// the first and after the last. Values left out of every keyframe come from the camera.
+    #[serde(default)]
+    keyframes: Vec<CameraKeyframe>,
+}
+
+/// The camera at a frame, each value is only keyed when given
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct CameraKeyframe {
+    frame: u32,
+    origin: Option<[f32; 3]>,
+    target: Option<[f32; 3]>,
+    vfov: Option<f32>,
+    fov: Option<f32>,
+    focal_length: Option<f32>,
+    focus_distance: Option<f32>,
+    aperture: Option<f32>,
+}
+
+impl CameraConfig {
+    /// The camera at a frame of the animation
+    pub fn at_frame(&self, animation: &AnimationConfig, frame: u32) -> CameraConfig {
+        let keys = &animation.keyframes;
+        let frame = frame as f32;
+        let vector = |key: fn(&CameraKeyframe) -> Option<[f32; 3]>, value: [f32; 3]| {
+            let keys: Vec<_> = keys
+                .iter()
+                .filter_map(|k| key(k).map(|v| (k.frame as f32, Vec3::from(v))))
+                .collect();
+            interpolate(&keys, frame).map_or(value, |v| [v.x(), v.y(), v.z()])
+        };
+        let scalar = |key: fn(&CameraKeyframe) -> Option<f32>| {
+            let keys: Vec<_> = keys
+                .iter()
+                .filter_map(|k| key(k).map(|v| (k.frame as f32, v)))
+                .collect();
+            interpolate(&keys, frame)
+        };
+
+        CameraConfig {
+            origin: vector(|k| k.origin, self.origin),
+            target: vector(|k| k.target, self.target),
+            vfov: scalar(|k| k.vfov).unwrap_or(self.vfov),
+            fov: scalar(|k| k.fov).unwrap_or(self.fov),
+            focal_length: scalar(|k| k.focal_length).or(self.focal_length),
+            focus_distance: scalar(|k| k.focus_distance).or(self.focus_distance),
+            aperture: scalar(|k| k.aperture).unwrap_or(self.aperture),
+            ..self.clone()
+        }
+    }
+}
+
+/// Piecewise linear interpolation between keys of (frame, value), which need not be sorted
+fn interpolate<T>(keys: &[(f32, T)], frame: f32) -> Option<T>
+where
+    T: Copy + std::ops::Add<Output = T> + std::ops::Mul<f32, Output = T>,
+{
+    let before = keys
+        .iter()
+        .filter(|(f, _)| *f <= frame)
+        .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let after = keys
+        .iter()
+        .filter(|(f, _)| *f >= frame)
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    match (before, after) {
+        (Some(&(f0, v0)), Some(&(f1, v1))) if f1 > f0 => {
+            let t = (frame - f0) / (f1 - f0);
+            Some(v0 * (1.0 - t) + v1 * t)
+        }
+        (Some(&(_, v)), _) | (None, Some(&(_, v))) => Some(v),
+        (None, None) => None,
+    }
+}
@@ -0,0 +1,82 @@
+use crate::{material::*, textures::ImageTexture};
+use glam::Vec3;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Specifies what a surface is made of
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MaterialConfig {
+    Lambertian {
+        albedo: [f32; 3],
+    },
+    Metal {
+        albedo: [f32; 3],
+        /// Blurs the reflection, from 0 for a mirror to 1
+        #[serde(default)]
+        fuzz: f32,
+    },
+    Dielectric {
+        /// Index of refraction
+        #[serde(default = "default_ior")]
+        ior: f32,
+    },
+    /// Cuts the surface out of the alpha channel
+    Holdout,
+    /// Bends the shading normals of another material with a tangent space normal map
+    NormalMap {
+        material: Box<MaterialConfig>,
+        /// Image wrapped over the texture coordinates
+        texture: String,
+        /// Scales how far the normals are bent
+        #[serde(default = "default_strength")]
+        strength: f32,
+    },
+    /// Bends the shading normals of another material along the slope of a grayscale height map
+    BumpMap {
+        material: Box<MaterialConfig>,
+        /// Image wrapped over the texture coordinates
+        texture: String,
+        /// Height of a white pixel, in scene units per unit of texture coordinates
+        scale: f32,
+    },
+}
+
+fn default_ior() -> f32 {
+    1.5
+}
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+impl MaterialConfig {
+    /// Builds the material, loading the images of normal and bump maps
+    pub fn material(&self) -> anyhow::Result<Arc<dyn Material>> {
+        Ok(match *self {
+            MaterialConfig::Lambertian { albedo } => Arc::new(Lambertian::new(Vec3::from(albedo))),
+            MaterialConfig::Metal { albedo, fuzz } => {
+                Arc::new(Metal::new(Vec3::from(albedo), fuzz))
+            }
+            MaterialConfig::Dielectric { ior } => Arc::new(Dielectric::new(ior)),
+            MaterialConfig::Holdout => Arc::new(Holdout),
+            MaterialConfig::NormalMap {
+                ref material,
+                ref texture,
+                strength,
+            } => Arc::new(
+                NormalMap::new(material.material()?, Arc::new(ImageTexture::load(texture)?))
+                    .with_strength(strength),
+            ),
+            MaterialConfig::BumpMap {
+                ref material,
+                ref texture,
+                scale,
+            } => Arc::new(BumpMap::new(
+                material.material()?,
+                Arc::new(ImageTexture::load(texture)?),
+                scale,
+            )),
+        })
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{
+    grid::DensityGrid,
+    medium::*,
+    primitives::{Aabb, AnimatedTransform},
+};
+use glam::Vec3;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Specifies the medium filling a volume
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MediumConfig {
+    /// The same density everywhere
+    Constant {
+        /// Absorption coefficient per color channel
+        sigma_a: [f32; 3],
+        /// Scattering coefficient per color channel
+        sigma_s: [f32; 3],
+        #[serde(flatten)]
+        phase: PhaseConfig,
+    },
+    /// Densities from a voxel grid, stretched over the box around the shape before it is
+    /// transformed, so the grid turns and moves along with the object
+    Grid {
+        density: DensityConfig,
+        /// Extinction coefficient at a density of one
+        sigma_t: f32,
+        /// Fraction of extinction that is scattering rather than absorption per color channel
+        albedo: [f32; 3],
+        #[serde(flatten)]
+        phase: PhaseConfig,
+    },
+}
+
+impl MediumConfig {
+    /// Builds the medium filling a volume within bounds in the local space of its transform
+    pub fn medium(
+        &self,
+        bounds: Option<Aabb>,
+        transform: &AnimatedTransform,
+    ) -> anyhow::Result<Arc<dyn Medium>> {
+        Ok(match self {
+            MediumConfig::Constant {
+                sigma_a,
+                sigma_s,
+                phase,
+            } => Arc::new(ConstantMedium::new(
+                Vec3::from(*sigma_a),
+                Vec3::from(*sigma_s),
+                phase.anisotropy,
+            )),
+            MediumConfig::Grid {
+                density,
+                sigma_t,
+                albedo,
+                phase,
+            } => {
+                let bounds =
+                    bounds.ok_or_else(|| anyhow::anyhow!("Grid media need a shape with bounds"))?;
+                Arc::new(
+                    GridMedium::new(
+                        Arc::new(density.grid()?),
+                        bounds,
+                        *sigma_t,
+                        Vec3::from(*albedo),
+                        phase.anisotropy,
+                    )
+                    .with_transform(transform.clone()),
+                )
+            }
+        })
+    }
+}
+
+/// Specifies which way the particles of a medium scatter light
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct PhaseConfig {
+    /// Henyey-Greenstein asymmetry, from -1 (back scattering) to 1 (forward scattering)
+    #[serde(default)]
+    pub anisotropy: f32,
+}
+
+/// Specifies where the densities of a grid come from
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DensityConfig {
+    /// A raw grid: the resolution as three little endian u32s, followed by the densities as
+    /// little endian f32s with x varying fastest
+    File { path: String },
+    /// A cloud like blob of fractal noise
+    Noise {
+        resolution: [usize; 3],
+        /// Number of noise cells across the grid
+        frequency: f32,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u32,
+    },
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+impl DensityConfig {
+    pub fn grid(&self) -> anyhow::Result<DensityGrid> {
+        match *self {
+            DensityConfig::File { ref path } => DensityGrid::load(path),
+            DensityConfig::Noise {
+                resolution,
+                frequency,
+                octaves,
+                seed,
+            } => {
+                anyhow::ensure!(
+                    resolution.iter().all(|&r| r > 0),
+                    "Noise grids need at least one voxel along every axis"
+                );
+                Ok(DensityGrid::noise(resolution, frequency, octaves, seed))
+            }
+        }
+    }
+}
+
+/// Specifies a homogeneous medium filling the whole scene
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct FogConfig {
+    /// Absorption coefficient per color channel
+    sigma_a: [f32; 3],
+    /// Scattering coefficient per color channel
+    sigma_s: [f32; 3],
+    #[serde(flatten)]
+    phase: PhaseConfig,
+    /// How far rays escaping the scene travel through the fog before reaching the sky
+    distance: f32,
+}
+
+impl FogConfig {
+    pub fn fog(&self) -> Fog {
+        let medium = ConstantMedium::new(
+            Vec3::from(self.sigma_a),
+            Vec3::from(self.sigma_s),
+            self.phase.anisotropy,
+        );
+
+        Fog::new(medium, self.distance)
+    }
+}
@@ -0,0 +1,268 @@
+//! The settings read from settings.toml, and how they turn into cameras, objects and media
+
+mod camera;
+mod material;
+mod medium;
+mod object;
+
+pub use camera::*;
+pub use material::*;
+pub use medium::*;
+pub use object::*;
+
+use crate::{film::Region, filter::Filter, sampler::*, tile::TileOrder};
+use serde::Deserialize;
+
+/// Specifies settings used in the pathtracing
+#[derive(Deserialize, Debug, Clone)]
+pub struct SettingsConfig {
+    /// Resolution of the output image (width, height)
+    resolution: [u32; 2],
+    /// Number of samples per pixel
+    pub samples: u32,
+    /// Max bounces of a single primary ray
+    pub max_bounces: u32,
+    /// Gamma
+    pub gamma: f32,
+    /// Where the camera is and how it projects the scene
+    #[serde(default)]
+    pub camera: CameraConfig,
+    /// Scene wide fog, or clear air if missing
+    pub fog: Option<FogConfig>,
+    /// Interval of time the camera shutter is open, for motion blur
+    pub shutter: Option<[f32; 2]>,
+    /// How the random numbers of each sample are generated
+    #[serde(default)]
+    pub sampler: SamplerKind,
+    /// Seed for all randomness, renders with the same seed and settings are identical
+    pub seed: Option<u64>,
+    /// Seed used when none is given, picked once when the settings are loaded
+    #[serde(skip, default = "random_seed")]
+    random_seed: u64,
+    /// Keep sampling the noisiest pixels after the first samples, or a fixed count if missing
+    pub adaptive: Option<AdaptiveConfig>,
+    /// Render one sample per pixel at a time and write previews, or all samples at once if missing
+    pub progressive: Option<ProgressiveConfig>,
+    /// Also write albedo, normal, depth, position, ID and direct/indirect lighting passes
+    #[serde(default)]
+    pub aovs: bool,
+    /// Also write a denoised image to denoised.png, or nothing if missing
+    pub denoise: Option<DenoiseConfig>,
+    /// How the samples in and around a pixel are weighted, a box over the pixel if missing
+    #[serde(default)]
+    pub filter: Filter,
+    /// Width and height of the square tiles the image is split into
+    #[serde(default = "default_tile_size")]
+    pub tile_size: u32,
+    /// The order tiles are rendered in
+    #[serde(default)]
+    pub tile_order: TileOrder,
+    /// Save the film to checkpoint.bin while rendering so it can be resumed, or never if missing
+    pub checkpoint: Option<CheckpointConfig>,
+    /// Camera keyframes and the frames to render when run with --frames
+    pub animation: Option<AnimationConfig>,
+    /// Only render a rectangle of the image, or all of it if missing
+    pub region: Option<RegionConfig>,
+    /// Save the images with an alpha channel of how much of every pixel is covered
+    #[serde(default)]
+    pub alpha: bool,
+    /// Leave the sky out where the camera sees it directly, it still lights the scene
+    #[serde(default)]
+    pub transparent_background: bool,
+    /// Turn the ground of the built-in scene into a shadow catcher
+    #[serde(default)]
+    pub shadow_catcher: bool,
+    /// Triangle meshes added to the built-in scene
+    #[serde(default)]
+    pub meshes: Vec<MeshConfig>,
+    /// Spheres, boxes and signed distance fields added to the built-in scene
+    #[serde(default)]
+    pub objects: Vec<ObjectConfig>,
+}
+
+fn random_seed() -> u64 {
+    rand::random()
+}
+
+fn default_tile_size() -> u32 {
+    32
+}
+
+/// Specifies a rectangle of pixels to render on its own, framed like the full image
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RegionConfig {
+    /// Top left corner of the region, in pixels from the top left of the image
+    min: [u32; 2],
+    /// Bottom right corner of the region, just outside it
+    max: [u32; 2],
+    /// Save only the region, instead of the full image with the rest left black
+    #[serde(default)]
+    pub crop: bool,
+}
+
+impl RegionConfig {
+    pub fn region(&self, width: u32, height: u32) -> anyhow::Result<Region> {
+        let ([x0, y0], [x1, y1]) = (self.min, self.max);
+        anyhow::ensure!(
+            x0 < x1 && y0 < y1 && x1 <= width && y1 <= height,
+            "Region from {:?} to {:?} is empty or outside the {}x{} image",
+            self.min,
+            self.max,
+            width,
+            height
+        );
+
+        Ok(Region {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        })
+    }
+}
+
+/// Specifies how extra samples are spread over the pixels that have not converged yet
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct AdaptiveConfig {
+    /// Pixels stop taking samples once their estimated relative error drops below this
+    pub threshold: f32,
+    /// Samples added to every unconverged pixel in each pass
+    pub pass_samples: u32,
+    /// Most samples a single pixel can take
+    pub max_samples: u32,
+    /// Stop refining after this many seconds
+    pub time_budget: Option<f32>,
+    /// Stop refining after this many samples in total over the whole image
+    pub sample_budget: Option<u64>,
+}
+
+/// Specifies how often a progressive render writes its current state to preview.png
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct ProgressiveConfig {
+    /// Write a preview every this many seconds
+    pub interval: Option<f32>,
+    /// Write a preview every this many passes
+    pub passes: Option<u32>,
+}
+
+/// Specifies how often the film is saved to checkpoint.bin and whether to continue from it
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct CheckpointConfig {
+    /// Save a checkpoint every this many seconds
+    pub interval: Option<f32>,
+    /// Save a checkpoint every this many passes
+    pub passes: Option<u32>,
+    /// Add samples to the existing checkpoint instead of starting over
+    #[serde(default)]
+    pub resume: bool,
+}
+
+/// Specifies how strongly the denoiser smooths, larger sigmas let more different pixels be mixed.
+/// It is guided by the albedo, normal and depth passes when aovs are enabled.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct DenoiseConfig {
+    /// Number of filter passes, each reaching twice as far as the one before
+    #[serde(default = "default_denoise_iterations")]
+    pub iterations: u32,
+    /// How many standard deviations of noise colors may differ by
+    #[serde(default = "default_sigma_color")]
+    pub sigma_color: f32,
+    /// Exponent of the cosine between normals, larger keeps edges sharper
+    #[serde(default = "default_sigma_normal")]
+    pub sigma_normal: f32,
+    /// Allowed relative difference in depth
+    #[serde(default = "default_sigma_depth")]
+    pub sigma_depth: f32,
+    /// Allowed difference in albedo
+    #[serde(default = "default_sigma_albedo")]
+    pub sigma_albedo: f32,
+}
+
+fn default_denoise_iterations() -> u32 {
+    5
+}
+
+fn default_sigma_color() -> f32 {
+    4.0
+}
+
+fn default_sigma_normal() -> f32 {
+    128.0
+}
+
+fn default_sigma_depth() -> f32 {
+    0.1
+}
+
+fn default_sigma_albedo() -> f32 {
+    0.1
+}
+
+impl Default for SettingsConfig {
+    fn default() -> Self {
+        Self {
+            resolution: [1280, 720],
+            samples: 12,
+            max_bounces: 8,
+            gamma: 2.2,
+            camera: CameraConfig::default(),
+            fog: None,
+            shutter: None,
+            sampler: SamplerKind::Independent,
+            seed: None,
+            random_seed: random_seed(),
+            adaptive: None,
+            progressive: None,
+            aovs: false,
+            denoise: None,
+            filter: Filter::default(),
+            tile_size: default_tile_size(),
+            tile_order: TileOrder::Spiral,
+            checkpoint: None,
+            animation: None,
+            region: None,
+            alpha: false,
+            transparent_background: false,
+            shadow_catcher: false,
+            meshes: Vec::new(),
+            objects: Vec::new(),
+        }
+    }
+}
+
+impl SettingsConfig {
+    pub fn width(&self) -> u32 {
+        self.resolution[0]
+    }
+
+    pub fn height(&self) -> u32 {
+        self.resolution[1]
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or(self.random_seed)
+    }
+
+    /// Hash of the settings that decide what the samples of a pixel are.
+    /// Sample counts are left out, so a checkpoint can be resumed with more samples.
+    pub fn checkpoint_hash(&self) -> u64 {
+        let settings = format!(
+            "{:?}",
+            (
+                self.resolution,
+                self.max_bounces,
+                &self.camera,
+                self.fog,
+                self.shutter,
+                self.sampler,
+                self.seed(),
+                self.filter,
+                self.transparent_background,
+                &self.meshes,
+                &self.objects
+            )
+        );
+
+        hash_bytes(settings.as_bytes())
+    }
+}
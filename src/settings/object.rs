@@ -0,0 +1,385 @@
+use crate::{
+    primitives::*,
+    settings::{MaterialConfig, MediumConfig},
+    textures::ImageTexture,
+};
+use glam::{Quat, Vec3};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Specifies a triangle mesh loaded from an OBJ file and where it is placed in the scene
+#[derive(Deserialize, Debug, Clone)]
+pub struct MeshConfig {
+    path: String,
+    #[serde(flatten)]
+    transform: TransformConfig,
+    material: MaterialConfig,
+    /// Refines the faces into a smooth subdivision surface, or keeps them flat if missing
+    subdivision: Option<SubdivisionConfig>,
+    /// Moves the surface along its normals by a height texture, or leaves it as modelled if
+    /// missing
+    displacement: Option<DisplacementConfig>,
+}
+
+/// Specifies how many times a control mesh is subdivided and which of its edges stay sharp
+#[derive(Deserialize, Debug, Clone)]
+pub struct SubdivisionConfig {
+    /// Number of times every face is split into quads
+    level: u32,
+    #[serde(default)]
+    creases: Vec<CreaseConfig>,
+}
+
+/// Specifies an edge that stays sharp while its mesh is subdivided
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct CreaseConfig {
+    /// Vertices at either end, counted from 1 as in the OBJ file
+    vertices: [u32; 2],
+    /// Number of levels the edge stays sharp for, fractions blend it into the smooth surface
+    #[serde(default = "default_sharpness")]
+    sharpness: f32,
+}
+
+fn default_sharpness() -> f32 {
+    f32::INFINITY
+}
+
+/// Specifies how finely a mesh is tessellated and how far a height texture displaces it
+#[derive(Deserialize, Debug, Clone)]
+pub struct DisplacementConfig {
+    /// Grayscale image wrapped over the texture coordinates of the mesh
+    texture: String,
+    /// Distance a texture value of one moves the surface, in the units of the mesh
+    scale: f32,
+    /// Number of times every triangle is split into four
+    #[serde(default)]
+    level: u32,
+    /// Triangles are then split until no edge is longer than this, in the units of the mesh
+    edge_length: Option<f32>,
+}
+
+/// Specifies where an object is placed, and how it moves while the shutter is open
+#[derive(Deserialize, Debug, Clone)]
+pub struct TransformConfig {
+    #[serde(default)]
+    translation: [f32; 3],
+    /// Rotation in degrees around the x, then the y and then the z axis
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default = "default_scale")]
+    scale: [f32; 3],
+    /// Placements at points in time, interpolated in between and held before the first and
+    /// after the last. Values left out of a keyframe come from the placement above.
+    #[serde(default)]
+    keyframes: Vec<TransformKeyframe>,
+}
+
+/// The placement of an object at a point in time
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct TransformKeyframe {
+    time: f32,
+    translation: Option<[f32; 3]>,
+    rotation: Option<[f32; 3]>,
+    scale: Option<[f32; 3]>,
+}
+
+fn default_scale() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+impl TransformConfig {
+    pub fn transform(&self) -> AnimatedTransform {
+        let transform = |translation: [f32; 3], [x, y, z]: [f32; 3], scale: [f32; 3]| Transform {
+            translation: Vec3::from(translation),
+            rotation: Quat::from_rotation_z(z.to_radians())
+                * Quat::from_rotation_y(y.to_radians())
+                * Quat::from_rotation_x(x.to_radians()),
+            scale: Vec3::from(scale),
+        };
+
+        if self.keyframes.is_empty() {
+            return transform(self.translation, self.rotation, self.scale).into();
+        }
+        AnimatedTransform::new(
+            self.keyframes
+                .iter()
+                .map(|key| {
+                    let placement = transform(
+                        key.translation.unwrap_or(self.translation),
+                        key.rotation.unwrap_or(self.rotation),
+                        key.scale.unwrap_or(self.scale),
+                    );
+                    (key.time, placement)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl MeshConfig {
+    /// Loads the mesh, then subdivides and displaces it, which happens once when the scene is built
+    pub fn instance(&self) -> anyhow::Result<Instance> {
+        let mut mesh = PolygonMesh::load_obj(&self.path)?;
+        if let Some(subdivision) = &self.subdivision {
+            let creases = subdivision
+                .creases
+                .iter()
+                .map(|crease| {
+                    let [a, b] = crease.vertices;
+                    anyhow::ensure!(a > 0 && b > 0, "Crease vertices are counted from 1");
+                    Ok(Crease {
+                        vertices: [a - 1, b - 1],
+                        sharpness: crease.sharpness,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            mesh = mesh.subdivide(subdivision.level, &creases)?;
+        }
+
+        let mut mesh = mesh.triangulate();
+        if self.subdivision.is_some() {
+            mesh.smooth_normals();
+        }
+        if let Some(displacement) = &self.displacement {
+            mesh.tessellate(displacement.level)?;
+            if let Some(edge_length) = displacement.edge_length {
+                anyhow::ensure!(edge_length > 0.0, "Edge length must be positive");
+                mesh.tessellate_to_length(edge_length)?;
+            }
+            let texture = ImageTexture::load(&displacement.texture)?;
+            mesh.displace(&texture, displacement.scale)?;
+        }
+
+        Ok(Instance::receiver(
+            Arc::new(Mesh::new(mesh)),
+            self.material.material()?,
+            self.transform.transform(),
+        ))
+    }
+}
+
+/// Specifies an object made of a shape, placed in the scene by a transform. The shape is either
+/// a surface with a material or the boundary of a volume filled with a medium.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ObjectConfig {
+    shape: ShapeConfig,
+    #[serde(flatten)]
+    transform: TransformConfig,
+    material: Option<MaterialConfig>,
+    medium: Option<MediumConfig>,
+}
+
+impl ObjectConfig {
+    pub fn instance(&self) -> anyhow::Result<Instance> {
+        match (&self.material, &self.medium) {
+            (Some(material), None) => Ok(Instance::receiver(
+                self.shape.primitive()?,
+                material.material()?,
+                self.transform.transform(),
+            )),
+            (None, Some(medium)) => {
+                let boundary = self.shape.primitive()?;
+                let transform = self.transform.transform();
+                let medium = medium.medium(boundary.bounds(), &transform)?;
+                Ok(Instance::volume(boundary, medium, transform))
+            }
+            _ => anyhow::bail!("Objects need either a material or a medium"),
+        }
+    }
+}
+
+/// Specifies the shape of an object, in its own space before it is transformed
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ShapeConfig {
+    Sphere {
+        #[serde(default)]
+        center: [f32; 3],
+        radius: f32,
+    },
+    /// Axis aligned box between two corners
+    Box { min: [f32; 3], max: [f32; 3] },
+    /// Signed distance field rendered by sphere tracing
+    Sdf {
+        node: SdfConfig,
+        /// Max number of steps taken along a ray before giving up
+        #[serde(default = "default_max_steps")]
+        max_steps: u32,
+        /// Distance to the surface at which a ray has hit it
+        #[serde(default = "default_epsilon")]
+        epsilon: f32,
+        /// Fraction of the distance bound to step, lower it for twists
+        #[serde(default = "default_step_scale")]
+        step_scale: f32,
+    },
+    /// Combination of two closed shapes, spheres, boxes, signed distance fields or other
+    /// combinations
+    Csg {
+        operation: CsgOp,
+        left: Box<ShapeConfig>,
+        right: Box<ShapeConfig>,
+    },
+}
+
+fn default_max_steps() -> u32 {
+    256
+}
+
+fn default_epsilon() -> f32 {
+    0.0001
+}
+
+fn default_step_scale() -> f32 {
+    1.0
+}
+
+impl ShapeConfig {
+    pub fn primitive(&self) -> anyhow::Result<Arc<dyn Intersect>> {
+        Ok(match self {
+            ShapeConfig::Sphere { center, radius } => {
+                Arc::new(Sphere::new(Vec3::from(*center), *radius))
+            }
+            ShapeConfig::Box { min, max } => {
+                Arc::new(Aabb::new(Vec3::from(*min), Vec3::from(*max)))
+            }
+            ShapeConfig::Sdf {
+                node,
+                max_steps,
+                epsilon,
+                step_scale,
+            } => Arc::new(
+                Sdf::new(node.node()?)
+                    .with_max_steps(*max_steps)
+                    .with_epsilon(*epsilon)
+                    .with_step_scale(*step_scale),
+            ),
+            ShapeConfig::Csg {
+                operation,
+                left,
+                right,
+            } => Arc::new(Csg::new(*operation, left.primitive()?, right.primitive()?)?),
+        })
+    }
+}
+
+/// Specifies a node of a signed distance field, shapes centered on the origin or operations
+/// on the nodes below them
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum SdfConfig {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        half_extents: [f32; 3],
+    },
+    RoundBox {
+        half_extents: [f32; 3],
+        radius: f32,
+    },
+    /// Lying in the xz-plane
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Capsule {
+        a: [f32; 3],
+        b: [f32; 3],
+        radius: f32,
+    },
+    Translate {
+        offset: [f32; 3],
+        node: Box<SdfConfig>,
+    },
+    Scale {
+        factor: f32,
+        node: Box<SdfConfig>,
+    },
+    Union {
+        a: Box<SdfConfig>,
+        b: Box<SdfConfig>,
+    },
+    Intersection {
+        a: Box<SdfConfig>,
+        b: Box<SdfConfig>,
+    },
+    /// Carves b out of a
+    Subtraction {
+        a: Box<SdfConfig>,
+        b: Box<SdfConfig>,
+    },
+    /// Blended over a distance of k
+    SmoothUnion {
+        a: Box<SdfConfig>,
+        b: Box<SdfConfig>,
+        k: f32,
+    },
+    SmoothIntersection {
+        a: Box<SdfConfig>,
+        b: Box<SdfConfig>,
+        k: f32,
+    },
+    SmoothSubtraction {
+        a: Box<SdfConfig>,
+        b: Box<SdfConfig>,
+        k: f32,
+    },
+    /// Around the y-axis by rate radians per unit of height
+    Twist {
+        rate: f32,
+        node: Box<SdfConfig>,
+    },
+    /// On a grid with the given period, limit copies out from the origin per axis
+    Repeat {
+        period: [f32; 3],
+        limit: [f32; 3],
+        node: Box<SdfConfig>,
+    },
+}
+
+impl SdfConfig {
+    pub fn node(&self) -> anyhow::Result<SdfNode> {
+        Ok(match self {
+            SdfConfig::Sphere { radius } => SdfNode::sphere(*radius),
+            SdfConfig::Box { half_extents } => SdfNode::cuboid(Vec3::from(*half_extents)),
+            SdfConfig::RoundBox {
+                half_extents,
+                radius,
+            } => SdfNode::round_box(Vec3::from(*half_extents), *radius),
+            SdfConfig::Torus {
+                major_radius,
+                minor_radius,
+            } => SdfNode::torus(*major_radius, *minor_radius),
+            SdfConfig::Capsule { a, b, radius } => {
+                SdfNode::capsule(Vec3::from(*a), Vec3::from(*b), *radius)
+            }
+            SdfConfig::Translate { offset, node } => node.node()?.translate(Vec3::from(*offset)),
+            SdfConfig::Scale { factor, node } => {
+                // A zero or negative factor would collapse or turn the distance field inside out
+                anyhow::ensure!(*factor > 0.0, "Scale factor must be positive");
+                node.node()?.scale(*factor)
+            }
+            SdfConfig::Union { a, b } => a.node()?.union(b.node()?),
+            SdfConfig::Intersection { a, b } => a.node()?.intersection(b.node()?),
+            SdfConfig::Subtraction { a, b } => a.node()?.subtraction(b.node()?),
+            SdfConfig::SmoothUnion { a, b, k } => a.node()?.smooth_union(b.node()?, *k),
+            SdfConfig::SmoothIntersection { a, b, k } => {
+                a.node()?.smooth_intersection(b.node()?, *k)
+            }
+            SdfConfig::SmoothSubtraction { a, b, k } => a.node()?.smooth_subtraction(b.node()?, *k),
+            SdfConfig::Twist { rate, node } => node.node()?.twist(*rate),
+            SdfConfig::Repeat {
+                period,
+                limit,
+                node,
+            } => {
+                // The bounds grow with the limit, so it has to be a finite number of copies
+                anyhow::ensure!(
+                    limit.iter().all(|l| l.is_finite() && *l >= 0.0),
+                    "Repeat limit must be finite and non-negative"
+                );
+                node.node()?.repeat(Vec3::from(*period), Vec3::from(*limit))
+            }
+        })
+    }
+}
@@ -1,15 +1,25 @@
+use crate::sampler::hash_bytes;
 use glam::{vec3, Vec3};
 use image::RgbImage;
 
-pub trait Texture {
+pub trait Texture: std::fmt::Debug + Send + Sync {
     fn value(&self, u: f32, v: f32) -> Vec3;
+
+    /// A single value for textures used as heights, the average of the channels
+    fn scalar(&self, u: f32, v: f32) -> f32 {
+        let value = self.value(u, v);
+        (value.x() + value.y() + value.z()) / 3.0
+    }
 }
 
 /// A texture with a constant uniform color
+#[allow(dead_code)]
+#[derive(Debug)]
 pub struct UniformTexture {
     color: Vec3,
 }
 
+#[allow(dead_code)]
 impl UniformTexture {
     pub fn new(color: Vec3) -> Self {
         Self { color }
@@ -22,27 +32,54 @@ impl Texture for UniformTexture {
     }
 }
 
+/// An image repeated over the texture coordinates, with v going up from the bottom row.
+/// Values are interpolated between the centers of the pixels.
 pub struct ImageTexture {
     image: RgbImage,
+    /// Tells images apart without printing every pixel
+    hash: u64,
 }
 
 impl ImageTexture {
     pub fn new(image: RgbImage) -> Self {
-        Self { image }
+        let hash = hash_bytes(image.as_raw());
+        Self { image, hash }
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        Ok(Self::new(image::open(path)?.to_rgb8()))
+    }
+}
+
+impl std::fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageTexture")
+            .field("width", &self.image.width())
+            .field("height", &self.image.height())
+            .field("hash", &self.hash)
+            .finish()
     }
 }
 
 impl Texture for ImageTexture {
     fn value(&self, u: f32, v: f32) -> Vec3 {
-        let u = u * self.image.width() as f32;
-        let v = v * self.image.height() as f32;
+        let (width, height) = (self.image.width() as i64, self.image.height() as i64);
+        let x = u * width as f32 - 0.5;
+        let y = (1.0 - v) * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
 
-        let [r, g, b] = self.image.get_pixel(u as u32, v as u32).0;
+        let pixel = |x: i64, y: i64| {
+            let [r, g, b] = self
+                .image
+                .get_pixel(x.rem_euclid(width) as u32, y.rem_euclid(height) as u32)
+                .0;
 
-        let r = r as f32 / 255.99;
-        let g = g as f32 / 255.99;
-        let b = b as f32 / 255.99;
+            vec3(r as f32, g as f32, b as f32) / 255.99
+        };
+        let (x0, y0) = (x0 as i64, y0 as i64);
 
-        vec3(r, g, b)
+        (1.0 - ty) * ((1.0 - tx) * pixel(x0, y0) + tx * pixel(x0 + 1, y0))
+            + ty * ((1.0 - tx) * pixel(x0, y0 + 1) + tx * pixel(x0 + 1, y0 + 1))
     }
 }
@@ -0,0 +1,148 @@
+use crate::{aov::AovPixel, film::FilmPixel};
+use serde::Deserialize;
+
+/// The order tiles are handed out to the render threads in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TileOrder {
+    /// Row by row from the top left
+    Scanline,
+    /// Outwards from the center of the image, where the subject usually is
+    Spiral,
+    /// Along a Hilbert curve, neighbouring tiles are rendered close together in time
+    Hilbert,
+}
+
+impl Default for TileOrder {
+    fn default() -> Self {
+        TileOrder::Spiral
+    }
+}
+
+impl TileOrder {
+    /// The positions of a grid of tiles in the order they should be rendered
+    pub fn order(self, columns: u32, rows: u32) -> Vec<(u32, u32)> {
+        match self {
+            TileOrder::Scanline => (0..rows)
+                .flat_map(|row| (0..columns).map(move |column| (column, row)))
+                .collect(),
+            TileOrder::Spiral => spiral(columns, rows),
+            TileOrder::Hilbert => hilbert(columns, rows),
+        }
+    }
+}
+
+/// A rectangle of the film that is rendered by a single thread.
+/// It holds mutable slices of the rows it covers, so tiles can be written to in parallel.
+pub struct Tile<'a> {
+    /// Column of the top left pixel of the tile
+    pub x: u32,
+    /// Row of the top left pixel of the tile, counted from the top of the film
+    pub y: u32,
+    rows: Vec<(&'a mut [FilmPixel], Option<&'a mut [AovPixel]>)>,
+}
+
+impl<'a> Tile<'a> {
+    pub fn new(x: u32, y: u32) -> Self {
+        Self {
+            x,
+            y,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Adds the next row of the tile, along with its auxiliary values if the film has them
+    pub fn push_row(&mut self, row: &'a mut [FilmPixel], aovs: Option<&'a mut [AovPixel]>) {
+        self.rows.push((row, aovs));
+    }
+
+    /// The pixels of the tile along with their column and row in the film
+    pub fn into_pixels(
+        self,
+    ) -> impl Iterator<Item = ((u32, u32), &'a mut FilmPixel, Option<&'a mut AovPixel>)> {
+        let (x, y) = (self.x, self.y);
+        self.rows
+            .into_iter()
+            .enumerate()
+            .flat_map(move |(row, (pixels, aovs))| {
+                let aovs = aovs
+                    .into_iter()
+                    .flatten()
+                    .map(Some)
+                    .chain(std::iter::repeat_with(|| None));
+
+                pixels
+                    .iter_mut()
+                    .zip(aovs)
+                    .enumerate()
+                    .map(move |(column, (pixel, aov))| {
+                        ((x + column as u32, y + row as u32), pixel, aov)
+                    })
+            })
+    }
+}
+
+// Walks a square spiral outwards from the center tile, skipping the positions outside the grid
+fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let count = (columns * rows) as usize;
+    let mut order = Vec::with_capacity(count);
+    let (mut x, mut y) = ((columns as i64 - 1) / 2, (rows as i64 - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut direction = 0;
+    let mut length = 1;
+
+    while order.len() < count {
+        // Every length is walked twice before the spiral widens
+        for _ in 0..2 {
+            let (dx, dy) = directions[direction];
+            for _ in 0..length {
+                if 0 <= x && x < columns as i64 && 0 <= y && y < rows as i64 {
+                    order.push((x as u32, y as u32));
+                }
+                x += dx;
+                y += dy;
+            }
+            direction = (direction + 1) % 4;
+        }
+        length += 1;
+    }
+
+    order
+}
+
+// Follows a Hilbert curve over the smallest power of two square covering the grid
+fn hilbert(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let size = columns.max(rows).max(1).next_power_of_two();
+
+    (0..size as u64 * size as u64)
+        .map(|d| hilbert_point(size, d))
+        .filter(|&(x, y)| x < columns && y < rows)
+        .collect()
+}
+
+// Position of point d along the Hilbert curve filling a size by size square
+fn hilbert_point(size: u32, d: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0u32, 0u32);
+    let mut t = d;
+    let mut s = 1;
+    while s < size {
+        let rx = 1 & (t / 2) as u32;
+        let ry = 1 & (t as u32 ^ rx);
+
+        // Rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}